@@ -0,0 +1,206 @@
+//! Translation between the chat completions shape used throughout the agent and
+//! Anthropic's native Messages API.
+
+use anyhow::{Result, anyhow};
+use serde_json::{json, Map, Value};
+
+/// The Messages API requires `max_tokens`; used when none is configured.
+const DEFAULT_MAX_TOKENS: u64 = 4096;
+
+/// Turns a chat completions request body into a Messages API request body.
+/// System messages move to the top-level `system` field, tool definitions and
+/// tool traffic are rewritten as `tool_use`/`tool_result` content blocks.
+pub fn to_messages_request(request: &Value) -> Value {
+    let mut system = Vec::new();
+    let mut messages = Vec::new();
+
+    for message in request["messages"].as_array().into_iter().flatten() {
+        match message["role"].as_str().unwrap_or_default() {
+            "system" => system.push(text_of(&message["content"])),
+            "tool" => messages.push(json!({
+                "role": "user",
+                "content": [{
+                    "type": "tool_result",
+                    "tool_use_id": message["tool_call_id"],
+                    "content": text_of(&message["content"]),
+                }]
+            })),
+            "assistant" if message["tool_calls"].is_array() => {
+                let mut content = Vec::new();
+                let text = text_of(&message["content"]);
+                if !text.is_empty() {
+                    content.push(json!({ "type": "text", "text": text }));
+                }
+                for call in message["tool_calls"].as_array().into_iter().flatten() {
+                    let input: Value = call["function"]["arguments"]
+                        .as_str()
+                        .and_then(|arguments| serde_json::from_str(arguments).ok())
+                        .unwrap_or_else(|| json!({}));
+                    content.push(json!({
+                        "type": "tool_use",
+                        "id": call["id"],
+                        "name": call["function"]["name"],
+                        "input": input,
+                    }));
+                }
+                messages.push(json!({ "role": "assistant", "content": content }));
+            },
+            role => messages.push(json!({ "role": role, "content": to_content_blocks(&message["content"]) })),
+        }
+    }
+
+    let mut body = Map::new();
+    body.insert("model".to_string(), request["model"].clone());
+    if !system.is_empty() {
+        body.insert("system".to_string(), json!(system.join("\n\n")));
+    }
+    body.insert("messages".to_string(), json!(messages));
+    body.insert("max_tokens".to_string(), json!(request["max_tokens"].as_u64().unwrap_or(DEFAULT_MAX_TOKENS)));
+    for field in ["temperature", "top_p", "stream"] {
+        if !request[field].is_null() {
+            body.insert(field.to_string(), request[field].clone());
+        }
+    }
+    if let Some(tools) = request["tools"].as_array() {
+        let tools: Vec<Value> = tools
+            .iter()
+            .map(|tool| json!({
+                "name": tool["function"]["name"],
+                "description": tool["function"]["description"],
+                "input_schema": tool["function"]["parameters"],
+            }))
+            .collect();
+        body.insert("tools".to_string(), json!(tools));
+    }
+
+    Value::Object(body)
+}
+
+/// Turns a Messages API response into the chat completions response shape.
+pub fn to_chat_completion(response: Value) -> Result<Value> {
+    if response["type"] == "error" {
+        return Err(anyhow!("API error: {}", response["error"]));
+    }
+    let Some(blocks) = response["content"].as_array() else {
+        return Err(anyhow!("Anthropic response has no content: {}", response));
+    };
+
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("text") => text.push_str(block["text"].as_str().unwrap_or_default()),
+            Some("tool_use") => tool_calls.push(json!({
+                "id": block["id"],
+                "type": "function",
+                "function": { "name": block["name"], "arguments": block["input"].to_string() },
+            })),
+            _ => {},
+        }
+    }
+
+    let mut message = json!({ "role": "assistant", "content": text });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = json!(tool_calls);
+    }
+    Ok(json!({
+        "id": response["id"],
+        "object": "chat.completion",
+        "model": response["model"],
+        "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason(&response["stop_reason"]) }],
+        "usage": usage(&response["usage"]),
+    }))
+}
+
+/// Rewrites one Messages API stream event as a chat completions stream chunk.
+/// Events that carry nothing the agent uses (pings, block stops) yield `None`.
+pub fn to_chat_completion_chunk(event: &Value) -> Option<Value> {
+    match event["type"].as_str()? {
+        "message_start" => Some(json!({
+            "id": event["message"]["id"],
+            "model": event["message"]["model"],
+            "choices": [],
+            "usage": usage(&event["message"]["usage"]),
+        })),
+        "content_block_start" if event["content_block"]["type"] == "tool_use" => Some(delta(json!({
+            "tool_calls": [{
+                "index": event["index"],
+                "id": event["content_block"]["id"],
+                "function": { "name": event["content_block"]["name"], "arguments": "" },
+            }]
+        }))),
+        "content_block_delta" => match event["delta"]["type"].as_str()? {
+            "text_delta" => Some(delta(json!({ "content": event["delta"]["text"] }))),
+            "input_json_delta" => Some(delta(json!({
+                "tool_calls": [{ "index": event["index"], "function": { "arguments": event["delta"]["partial_json"] } }]
+            }))),
+            _ => None,
+        },
+        "message_delta" => Some(json!({
+            "choices": [{ "index": 0, "delta": {}, "finish_reason": finish_reason(&event["delta"]["stop_reason"]) }],
+            "usage": usage(&event["usage"]),
+        })),
+        "error" => Some(json!({ "error": event["error"] })),
+        _ => None,
+    }
+}
+
+fn delta(delta: Value) -> Value {
+    json!({ "choices": [{ "index": 0, "delta": delta }] })
+}
+
+/// Maps Anthropic stop reasons onto their chat completions names.
+fn finish_reason(stop_reason: &Value) -> Value {
+    match stop_reason.as_str() {
+        Some("end_turn") | Some("stop_sequence") => json!("stop"),
+        Some("tool_use") => json!("tool_calls"),
+        Some("max_tokens") => json!("length"),
+        Some(other) => json!(other),
+        None => Value::Null,
+    }
+}
+
+fn usage(usage: &Value) -> Value {
+    if !usage.is_object() {
+        return Value::Null;
+    }
+    let prompt = usage["input_tokens"].as_u64().unwrap_or(0);
+    let completion = usage["output_tokens"].as_u64().unwrap_or(0);
+    json!({ "prompt_tokens": prompt, "completion_tokens": completion, "total_tokens": prompt + completion })
+}
+
+/// Converts chat completions content parts to Messages API blocks; images become
+/// `image` blocks with a base64 or URL source. Plain text content is kept as is.
+fn to_content_blocks(content: &Value) -> Value {
+    let Some(parts) = content.as_array() else {
+        return content.clone();
+    };
+
+    let blocks = parts
+        .iter()
+        .map(|part| {
+            let Some(url) = part["image_url"]["url"].as_str() else {
+                return part.clone();
+            };
+            let source = match url.strip_prefix("data:").and_then(|data| data.split_once(";base64,")) {
+                Some((media_type, data)) => json!({ "type": "base64", "media_type": media_type, "data": data }),
+                None => json!({ "type": "url", "url": url }),
+            };
+            json!({ "type": "image", "source": source })
+        })
+        .collect();
+    Value::Array(blocks)
+}
+
+/// Message content as plain text; content given as blocks is joined.
+fn text_of(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|block| block["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
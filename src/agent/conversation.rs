@@ -4,7 +4,12 @@ use uuid::Uuid;
 use std::path::PathBuf;
 use fs_err as fs;
 use std::io::{self, Write};
+use std::time::Duration;
 use anyhow::Result;
+use tracing::{error, info, warn};
+
+use crate::util::{estimate_tokens, truncate_chars};
+use super::tokenizer::{count_conversation_tokens, count_tokens};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Role {
@@ -14,6 +19,67 @@ pub enum Role {
     Assistant,
     #[serde(rename = "system")]
     System,
+    /// The result of a tool call, sent back to the model
+    #[serde(rename = "tool")]
+    Tool,
+}
+
+/// Timing statistics gathered while a response was being received.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamStats {
+    pub chunks: usize,
+    pub time_to_first_token_ms: u64,
+    pub tokens_per_second: f64,
+}
+
+impl StreamStats {
+    pub fn new(chunks: usize, first_token: Duration, total: Duration, content: &str) -> Self {
+        let estimated_tokens = estimate_tokens(content) as f64;
+        let seconds = total.as_secs_f64();
+        
+        Self {
+            chunks,
+            time_to_first_token_ms: first_token.as_millis() as u64,
+            tokens_per_second: if seconds > 0.0 { estimated_tokens / seconds } else { 0.0 },
+        }
+    }
+}
+
+/// Token counts reported by the API.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl TokenUsage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    pub fn add(&mut self, other: TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+
+    /// Cost in the currency of the given prices per 1,000 tokens.
+    pub fn cost(&self, prompt_price_per_1k: f64, completion_price_per_1k: f64) -> f64 {
+        (self.prompt_tokens as f64 * prompt_price_per_1k + self.completion_tokens as f64 * completion_price_per_1k) / 1000.0
+    }
+}
+
+/// Details about how an assistant response was obtained, kept for debugging flaky sessions.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResponseMetadata {
+    #[serde(default)]
+    pub retries: u32,
+    #[serde(default)]
+    pub from_cache: bool,
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Tokens billed for this response, summed over tool-call rounds; unset when the API reported none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,6 +88,54 @@ pub struct Message {
     pub role: Role,
     pub content: String,
     pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_stats: Option<StreamStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<ResponseMetadata>,
+    /// Set on a system message that stands in for this many summarized earlier messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summarized: Option<usize>,
+    /// Content sent after the text, such as attached images
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<ContentPart>,
+    /// For tool results: the id of the call being answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// For tool results: the name of the tool that produced them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// For assistant messages: the tools called, in the chat completions `tool_calls` format
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<serde_json::Value>,
+}
+
+/// A non-text piece of a message's content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    /// An image the API fetches itself
+    ImageUrl { url: String },
+    /// An image embedded in the request
+    ImageBase64 { data: String, mime: String },
+}
+
+impl ContentPart {
+    /// The part in the chat completions content-array format.
+    fn to_openai_part(&self) -> serde_json::Value {
+        let url = match self {
+            ContentPart::ImageUrl { url } => url.clone(),
+            ContentPart::ImageBase64 { data, mime } => format!("data:{};base64,{}", mime, data),
+        };
+        serde_json::json!({ "type": "image_url", "image_url": { "url": url } })
+    }
+
+    /// A short description for listings and exports, without the image data.
+    pub fn describe(&self) -> String {
+        match self {
+            ContentPart::ImageUrl { url } => url.clone(),
+            ContentPart::ImageBase64 { data, mime } => format!("{}, {} KB", mime, (data.len() * 3 / 4).div_ceil(1024)),
+        }
+    }
 }
 
 impl Message {
@@ -31,7 +145,47 @@ impl Message {
             role,
             content,
             created_at: Utc::now(),
+            stream_stats: None,
+            metadata: None,
+            summarized: None,
+            parts: Vec::new(),
+            tool_call_id: None,
+            name: None,
+            tool_calls: Vec::new(),
+        }
+    }
+
+    /// The message in the chat completions format; with attachments its content becomes
+    /// an array of the text followed by the other parts.
+    pub(super) fn to_openai_message(&self) -> serde_json::Value {
+        let content = if self.parts.is_empty() {
+            serde_json::json!(self.content)
+        } else {
+            let text = Some(serde_json::json!({ "type": "text", "text": self.content })).filter(|_| !self.content.is_empty());
+            serde_json::Value::Array(text.into_iter().chain(self.parts.iter().map(ContentPart::to_openai_part)).collect())
+        };
+        let mut message = serde_json::json!({
+            "role": match self.role {
+                Role::User => "user",
+                Role::Assistant => "assistant",
+                Role::System => "system",
+                Role::Tool => "tool",
+            },
+            "content": content
+        });
+        if let Some(tool_call_id) = &self.tool_call_id {
+            message["tool_call_id"] = serde_json::json!(tool_call_id);
+        }
+        if let Some(name) = &self.name {
+            message["name"] = serde_json::json!(name);
         }
+        if !self.tool_calls.is_empty() {
+            if self.content.is_empty() && self.parts.is_empty() {
+                message["content"] = serde_json::Value::Null;
+            }
+            message["tool_calls"] = serde_json::json!(self.tool_calls);
+        }
+        message
     }
 
     pub fn user(content: String) -> Self {
@@ -45,6 +199,67 @@ impl Message {
     pub fn system(content: String) -> Self {
         Self::new(Role::System, content)
     }
+
+    /// An assistant turn asking for `tool_calls`, with whatever text came with them.
+    pub fn tool_request(content: String, tool_calls: Vec<serde_json::Value>) -> Self {
+        Self {
+            tool_calls,
+            ..Self::assistant(content)
+        }
+    }
+
+    /// Whether this is an assistant turn that only asks for tools.
+    pub fn is_tool_request(&self) -> bool {
+        !self.tool_calls.is_empty()
+    }
+
+    /// The result of the tool call `tool_call_id`, produced by the tool `name`.
+    pub fn tool(tool_call_id: String, name: String, content: String) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id),
+            name: Some(name),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+}
+
+/// Generation settings pinned to one conversation, taking precedence over the global config.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+}
+
+impl GenerationParams {
+    /// Applies a `name=value` setting; an empty value removes the override.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        match name.trim() {
+            "temperature" => self.temperature = parse_param(name, value)?,
+            "top_p" => self.top_p = parse_param(name, value)?,
+            "max_tokens" => self.max_tokens = parse_param(name, value)?,
+            other => anyhow::bail!("Unknown parameter '{}': expected temperature, top_p or max_tokens", other),
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+fn parse_param<T: std::str::FromStr>(name: &str, value: &str) -> Result<Option<T>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| anyhow::anyhow!("Invalid value '{}' for {}", value, name))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,6 +269,12 @@ pub struct Conversation {
     pub messages: Vec<Message>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Generation settings for this conversation only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<GenerationParams>,
+    /// Tokens spent on the responses added to this conversation, including removed ones
+    #[serde(default)]
+    pub usage: TokenUsage,
 }
 
 impl Conversation {
@@ -65,54 +286,284 @@ impl Conversation {
             messages: Vec::new(),
             created_at: now,
             updated_at: now,
+            params: None,
+            usage: TokenUsage::default(),
         }
     }
 
     pub fn add_message(&mut self, message: Message) {
+        if let Some(usage) = message.metadata.as_ref().and_then(|m| m.usage) {
+            self.usage.add(usage);
+        }
         self.messages.push(message);
         self.updated_at = Utc::now();
     }
 
+    /// Copies messages `0..=message_index` into a new conversation with its own id,
+    /// keeping pinned generation settings. An index past the end copies everything.
+    /// Usage is not carried over, since the fork hasn't spent any tokens yet.
+    pub fn fork_at(&self, message_index: usize) -> Conversation {
+        let mut fork = Conversation::new(format!("Fork of {}", self.title));
+        let end = message_index.saturating_add(1).min(self.messages.len());
+        fork.messages = self.messages[..end].to_vec();
+        fork.params = self.params.clone();
+        fork
+    }
+
+    /// Removes the trailing assistant message, if the conversation ends with one, along
+    /// with the tool calls and results that led to it. Returns the removed messages in order.
+    pub fn pop_last_response(&mut self) -> Option<Vec<Message>> {
+        if !matches!(self.messages.last()?.role, Role::Assistant) {
+            return None;
+        }
+        let mut start = self.messages.len() - 1;
+        while start > 0 && (matches!(self.messages[start - 1].role, Role::Tool) || self.messages[start - 1].is_tool_request()) {
+            start -= 1;
+        }
+        
+        self.updated_at = Utc::now();
+        Some(self.messages.split_off(start))
+    }
+
+    /// Drops every message after `index`, returning how many were removed.
+    /// An index at or past the last message leaves the conversation untouched.
+    pub fn truncate_to(&mut self, index: usize) -> usize {
+        let keep = index.saturating_add(1);
+        if keep >= self.messages.len() {
+            return 0;
+        }
+        
+        let removed = self.messages.len() - keep;
+        self.messages.truncate(keep);
+        self.updated_at = Utc::now();
+        removed
+    }
+
+    /// Number of tokens the conversation will take up in a request to `model`.
+    pub fn total_tokens_estimate(&self, model: &str) -> usize {
+        count_conversation_tokens(model, self)
+    }
+
+    /// Replaces the leading system message, inserting one if the conversation has none.
+    pub fn set_system_prompt(&mut self, prompt: String) {
+        match self.messages.first_mut() {
+            Some(message) if matches!(message.role, Role::System) => message.content = prompt,
+            _ => self.messages.insert(0, Message::system(prompt)),
+        }
+        self.updated_at = Utc::now();
+    }
+
     pub fn save_to_file(&self, path: &PathBuf) -> Result<()> {
         // Ensure the directory exists
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
         
+        // Saved on a timer and on exit, so an interrupted write must not clobber the old file
         let json = serde_json::to_string_pretty(self)?;
-        let mut file = fs::File::create(path)?;
-        file.write_all(json.as_bytes())?;
-        
-        Ok(())
+        write_atomically(path, json.as_bytes())
     }
 
     pub fn load_from_file(path: &PathBuf) -> Result<Self> {
-        let json = fs::read_to_string(path)?;
+        let json = read_text(path)?;
         let conversation: Conversation = serde_json::from_str(&json)?;
         
         Ok(conversation)
     }
 
+    /// Loads a conversation file and rewrites it in the current canonical format.
+    /// Returns the conversation and whether the file contents changed.
+    pub fn compact_file(path: &PathBuf) -> Result<(Self, bool)> {
+        let original = read_text(path)?;
+        let conversation: Conversation = serde_json::from_str(&original)?;
+        
+        let json = serde_json::to_string_pretty(&conversation)?;
+        let changed = json != original;
+        if changed {
+            write_atomically(path, json.as_bytes())?;
+        }
+        
+        Ok((conversation, changed))
+    }
+
+    /// The system messages in the order they were added, wherever they appear in the conversation.
+    pub fn system_messages(&self) -> impl Iterator<Item = &Message> {
+        self.messages.iter().filter(|m| matches!(m.role, Role::System))
+    }
+
+    /// Whether the conversation has anything beyond its system instructions.
+    pub fn has_exchanges(&self) -> bool {
+        self.messages.iter().any(|m| !matches!(m.role, Role::System))
+    }
+
+    /// Renders the conversation as Markdown with front-matter, one section per message.
+    /// Message content is kept verbatim so code fences survive. System instructions are
+    /// only included when `include_system` is set.
+    pub fn to_markdown(&self, include_system: bool) -> String {
+        let mut markdown = format!(
+            "---\ntitle: {}\nid: {}\ncreated_at: {}\nupdated_at: {}\n---\n\n# {}\n",
+            serde_json::to_string(&self.title).unwrap_or_default(),
+            self.id,
+            self.created_at.to_rfc3339(),
+            self.updated_at.to_rfc3339(),
+            self.title
+        );
+        
+        for message in self.messages.iter().filter(|m| include_system || !matches!(m.role, Role::System)) {
+            let heading = match message.role {
+                Role::User => "You",
+                Role::Assistant => "Assistant",
+                Role::System if message.summarized.is_some() => "System (summary of earlier messages)",
+                Role::System => "System (instructions)",
+                Role::Tool => "Tool result",
+            };
+            markdown.push_str(&format!("\n## {}\n\n{}\n", heading, message.content.trim_end()));
+            for call in &message.tool_calls {
+                markdown.push_str(&format!(
+                    "\n*(called {}: `{}`)*\n",
+                    call["function"]["name"].as_str().unwrap_or_default(),
+                    call["function"]["arguments"].as_str().unwrap_or_default()
+                ));
+            }
+            for part in &message.parts {
+                markdown.push_str(&format!("\n*(attached image: {})*\n", part.describe()));
+            }
+        }
+        
+        markdown
+    }
+
+    /// The exchanged messages as plain text, one `You:`/`Assistant:` paragraph each.
+    pub fn transcript(&self) -> String {
+        render_transcript(&self.messages)
+    }
+
+    /// Parses a plain transcript into messages. Each message starts on a line prefixed
+    /// with `user:` or `assistant:`; following lines without a prefix continue it.
+    pub fn messages_from_transcript(text: &str) -> Result<Vec<Message>> {
+        let mut messages: Vec<Message> = Vec::new();
+        
+        for (number, line) in text.lines().enumerate() {
+            let role = line.split_once(':').and_then(|(prefix, rest)| {
+                match prefix.trim().to_lowercase().as_str() {
+                    "user" => Some((Role::User, rest)),
+                    "assistant" => Some((Role::Assistant, rest)),
+                    _ => None,
+                }
+            });
+            
+            match (role, messages.last_mut()) {
+                (Some((role, rest)), _) => messages.push(Message::new(role, rest.trim_start().to_string())),
+                (None, Some(message)) => {
+                    message.content.push('\n');
+                    message.content.push_str(line);
+                },
+                (None, None) if line.trim().is_empty() => {},
+                (None, None) => anyhow::bail!(
+                    "line {}: expected a \"user:\" or \"assistant:\" prefix, found {:?}",
+                    number + 1,
+                    truncate_chars(line, 40)
+                ),
+            }
+        }
+        
+        for message in &mut messages {
+            message.content = message.content.trim_end().to_string();
+        }
+        if let Some(index) = messages.iter().position(|m| m.content.is_empty()) {
+            anyhow::bail!("message {} of the transcript is empty", index + 1);
+        }
+        
+        Ok(messages)
+    }
+
+    /// The oldest exchanged messages that could be folded into a summary while the
+    /// `keep_recent` newest stay verbatim, as their count and transcript. System
+    /// messages, earlier summaries included, are never part of it.
+    pub fn summarizable(&self, keep_recent: usize) -> Option<(usize, String)> {
+        let exchanged: Vec<&Message> = self.messages.iter().filter(|m| !matches!(m.role, Role::System)).collect();
+        let mut count = exchanged.len().saturating_sub(keep_recent);
+        // Tool results can't be separated from the call they answer
+        while count > 0 && count < exchanged.len() && matches!(exchanged[count].role, Role::Tool) {
+            count += 1;
+        }
+        if count == 0 {
+            return None;
+        }
+        
+        Some((count, render_transcript(exchanged[..count].iter().copied())))
+    }
+
+    /// Replaces the `count` oldest exchanged messages with a summary, placed where the first of them was.
+    pub fn replace_oldest_with_summary(&mut self, count: usize, summary: String) {
+        let Some(position) = self.messages.iter().position(|m| !matches!(m.role, Role::System)) else {
+            return;
+        };
+        
+        let mut removed = 0;
+        self.messages.retain(|m| {
+            if removed < count && !matches!(m.role, Role::System) {
+                removed += 1;
+                false
+            } else {
+                true
+            }
+        });
+        
+        let mut message = Message::system(format!("Summary of the earlier conversation:\n{}", summary));
+        message.summarized = Some(removed);
+        self.messages.insert(position, message);
+        self.updated_at = Utc::now();
+    }
+
+    /// Converts the conversation to the chat completions format, with all system
+    /// messages layered at the front in order.
     pub fn to_openai_messages(&self) -> Vec<serde_json::Value> {
-        self.messages
-            .iter()
-            .map(|msg| {
-                serde_json::json!({
-                    "role": match msg.role {
-                        Role::User => "user",
-                        Role::Assistant => "assistant",
-                        Role::System => "system",
-                    },
-                    "content": msg.content
-                })
-            })
+        self.system_messages()
+            .chain(self.messages.iter().filter(|m| !matches!(m.role, Role::System)))
+            .map(Message::to_openai_message)
             .collect()
     }
+
+    /// Like [`Self::to_openai_messages`], but leaves out the oldest exchanged messages
+    /// until their size for `model` fits in `max_tokens`. System messages and the newest
+    /// message are always kept, and the kept history starts with a user message.
+    pub fn to_openai_messages_truncated(&self, model: &str, max_tokens: usize) -> Vec<serde_json::Value> {
+        // Same per-message overhead as `count_conversation_tokens`
+        let cost = |m: &Message| count_tokens(model, &m.content) + 4;
+        let exchanged: Vec<&Message> = self.messages.iter().filter(|m| !matches!(m.role, Role::System)).collect();
+        let mut remaining = max_tokens.saturating_sub(self.system_messages().map(cost).sum());
+        
+        let mut start = exchanged.len();
+        while start > 0 {
+            let needed = cost(exchanged[start - 1]);
+            if needed > remaining && start < exchanged.len() {
+                break;
+            }
+            remaining = remaining.saturating_sub(needed);
+            start -= 1;
+        }
+        while start + 1 < exchanged.len() && !matches!(exchanged[start].role, Role::User) {
+            start += 1;
+        }
+        
+        let mut messages = self.to_openai_messages();
+        if start > 0 {
+            info!("Left out the {} oldest messages to fit the {}-token context budget", start, max_tokens);
+            let system_count = messages.len() - exchanged.len();
+            messages.drain(system_count..system_count + start);
+        }
+        messages
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConversationList {
     pub conversations: Vec<ConversationSummary>,
+    /// Set when the file this was loaded from is corrupt and couldn't be backed up,
+    /// so saving over it would lose it
+    #[serde(skip)]
+    keep_corrupt_file: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -122,6 +573,9 @@ pub struct ConversationSummary {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub message_count: usize,
+    /// Increases with every update to the list, so ordering survives a wrong system clock
+    #[serde(default)]
+    pub sequence: u64,
 }
 
 impl From<&Conversation> for ConversationSummary {
@@ -132,6 +586,7 @@ impl From<&Conversation> for ConversationSummary {
             created_at: conversation.created_at,
             updated_at: conversation.updated_at,
             message_count: conversation.messages.len(),
+            sequence: 0,
         }
     }
 }
@@ -140,11 +595,13 @@ impl ConversationList {
     pub fn new() -> Self {
         Self {
             conversations: Vec::new(),
+            keep_corrupt_file: false,
         }
     }
 
     pub fn add_conversation(&mut self, conversation: &Conversation) {
-        let summary = ConversationSummary::from(conversation);
+        let mut summary = ConversationSummary::from(conversation);
+        summary.sequence = self.conversations.iter().map(|c| c.sequence).max().unwrap_or(0) + 1;
         
         // Remove any existing entry with the same ID
         self.conversations.retain(|c| c.id != summary.id);
@@ -152,27 +609,109 @@ impl ConversationList {
         // Add the new summary
         self.conversations.push(summary);
         
-        // Sort by updated_at (most recent first)
-        self.conversations.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+        self.sort();
+    }
+
+    /// Removes the entry for `id`, returning it if there was one.
+    pub fn remove(&mut self, id: &str) -> Option<ConversationSummary> {
+        let position = self.conversations.iter().position(|c| c.id == id)?;
+        Some(self.conversations.remove(position))
+    }
+
+    /// Orders the list most recently updated first. Timestamps from the future are
+    /// treated as "now", and the sequence number breaks ties, so a clock that was
+    /// wrong when a conversation was saved can't pin it to the top.
+    fn sort(&mut self) {
+        let now = Utc::now();
+        self.conversations.sort_by_key(|c| std::cmp::Reverse((c.updated_at.min(now), c.sequence)));
+    }
+
+    /// Ranks conversations by how closely their titles match `query` (case-insensitive
+    /// Jaro-Winkler similarity), best first, keeping those scoring at least `threshold`.
+    pub fn rank_by_title(&self, query: &str, threshold: f64) -> Vec<(&ConversationSummary, f64)> {
+        let query = query.to_lowercase();
+        let mut ranked: Vec<_> = self.conversations
+            .iter()
+            .map(|c| (c, strsim::jaro_winkler(&c.title.to_lowercase(), &query)))
+            .filter(|(_, score)| *score >= threshold)
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
     }
 
     pub fn save_to_file(&self, path: &PathBuf) -> Result<()> {
+        // The corrupt file was reported when loading; it is the only copy of the old index
+        if self.keep_corrupt_file {
+            warn!("Not saving over the corrupt {}, which could not be backed up", path.display());
+            return Ok(());
+        }
+        
         // Ensure the directory exists
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
         
+        // Saved on a timer and on exit, so an interrupted write must not clobber the old file
         let json = serde_json::to_string_pretty(self)?;
-        let mut file = fs::File::create(path)?;
-        file.write_all(json.as_bytes())?;
+        write_atomically(path, json.as_bytes())
+    }
+
+    /// Logs conversations whose timestamps can't be right: updated in the future,
+    /// before they were created, or out of step with the order they were saved in.
+    fn warn_about_clock_skew(&self) {
+        let tolerance = chrono::Duration::hours(1);
+        let now = Utc::now();
         
-        Ok(())
+        for c in &self.conversations {
+            if c.updated_at > now + tolerance {
+                warn!("Conversation {} was updated in the future ({}); the system clock may have been wrong", c.id, c.updated_at);
+            } else if c.updated_at + tolerance < c.created_at {
+                warn!("Conversation {} was updated ({}) before it was created ({})", c.id, c.updated_at, c.created_at);
+            }
+        }
+        
+        let mut by_sequence: Vec<_> = self.conversations.iter().filter(|c| c.sequence > 0).collect();
+        by_sequence.sort_by_key(|c| c.sequence);
+        for pair in by_sequence.windows(2) {
+            if pair[0].updated_at > pair[1].updated_at + tolerance {
+                warn!(
+                    "Conversation {} was saved after {} but has an earlier timestamp; the system clock may have changed",
+                    pair[1].id, pair[0].id
+                );
+            }
+        }
     }
 
+    /// Loads the index. A missing file is an empty list; a file that doesn't parse (say,
+    /// truncated by a crash) is backed up to `<name>.bak` for recovery and replaced by
+    /// an empty list, so it is never silently overwritten. If no backup can be made, the
+    /// empty list is never saved instead.
     pub fn load_from_file(path: &PathBuf) -> Result<Self> {
-        match fs::read_to_string(path) {
+        match read_text(path) {
             Ok(json) => {
-                let list: ConversationList = serde_json::from_str(&json)?;
+                let mut list: ConversationList = match serde_json::from_str(&json) {
+                    Ok(list) => list,
+                    Err(e) => match back_up(path) {
+                        Ok(backup) => {
+                            error!(
+                                "{} is corrupt ({}); it was moved to {} and the conversation index starts empty. \
+                                Run `ai-agent compact` to rebuild it from the saved conversations.",
+                                path.display(), e, backup.display()
+                            );
+                            ConversationList::new()
+                        },
+                        Err(backup_error) => {
+                            error!(
+                                "{} is corrupt ({}) and could not be backed up ({}), so it won't be saved over. \
+                                Move it aside and run `ai-agent compact` to rebuild it from the saved conversations.",
+                                path.display(), e, backup_error
+                            );
+                            ConversationList { keep_corrupt_file: true, ..ConversationList::new() }
+                        },
+                    }
+                };
+                list.warn_about_clock_skew();
+                list.sort();
                 Ok(list)
             }
             Err(err) if err.kind() == io::ErrorKind::NotFound => {
@@ -181,4 +720,207 @@ impl ConversationList {
             Err(err) => Err(err.into()),
         }
     }
-} 
\ No newline at end of file
+}
+
+/// User and assistant messages as plain text, one `You:`/`Assistant:` paragraph each.
+fn render_transcript<'a>(messages: impl IntoIterator<Item = &'a Message>) -> String {
+    messages
+        .into_iter()
+        .filter_map(|m| match m.role {
+            Role::User => Some(format!("You: {}", m.content.trim_end())),
+            Role::Assistant if m.is_tool_request() && m.content.trim().is_empty() => None,
+            Role::Assistant => Some(format!("Assistant: {}", m.content.trim_end())),
+            Role::System | Role::Tool => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Moves a corrupt file aside to `<name>.bak`, or to a timestamped `<name>.<time>.bak`
+/// when that is taken, copying it there if it can't be moved. Returns the backup's path.
+fn back_up(path: &PathBuf) -> io::Result<PathBuf> {
+    let with_suffix = |suffix: &str| {
+        let mut backup = path.clone().into_os_string();
+        backup.push(suffix);
+        PathBuf::from(backup)
+    };
+    let mut backup = with_suffix(".bak");
+    if backup.exists() {
+        backup = with_suffix(&format!(".{}.bak", Utc::now().format("%Y%m%d-%H%M%S")));
+    }
+    
+    if fs::rename(path, &backup).is_err() {
+        fs::copy(path, &backup)?;
+    }
+    Ok(backup)
+}
+
+/// Reads a text file, tolerating what external editors leave behind: a leading
+/// UTF-8 byte order mark is dropped, and invalid UTF-8 is replaced rather than
+/// failing the load.
+fn read_text(path: &PathBuf) -> io::Result<String> {
+    let bytes = fs::read(path)?;
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&bytes);
+    
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok(text.to_string()),
+        Err(err) => {
+            warn!("{} is not valid UTF-8 ({}); invalid bytes were replaced", path.display(), err);
+            Ok(String::from_utf8_lossy(bytes).into_owned())
+        }
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so a crash
+/// mid-write never leaves a truncated file behind.
+pub(crate) fn write_atomically(path: &PathBuf, contents: &[u8]) -> Result<()> {
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    
+    let written = fs::File::create(&tmp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&tmp_path, path)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation_of(count: usize) -> Conversation {
+        let mut conversation = Conversation::new("Test".to_string());
+        for i in 0..count {
+            conversation.add_message(Message::user(format!("message {}", i)));
+        }
+        conversation
+    }
+
+    #[test]
+    fn truncate_to_drops_the_messages_after_index() {
+        let mut conversation = conversation_of(5);
+        
+        assert_eq!(conversation.truncate_to(2), 2);
+        let contents: Vec<_> = conversation.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["message 0", "message 1", "message 2"]);
+    }
+
+    #[test]
+    fn truncate_to_past_the_end_leaves_the_conversation_untouched() {
+        let mut conversation = conversation_of(3);
+        let updated_at = conversation.updated_at;
+        
+        assert_eq!(conversation.truncate_to(2), 0);
+        assert_eq!(conversation.truncate_to(3), 0);
+        assert_eq!(conversation.truncate_to(usize::MAX), 0);
+        assert_eq!(conversation.messages.len(), 3);
+        assert_eq!(conversation.updated_at, updated_at);
+    }
+
+    #[test]
+    fn truncate_to_zero_keeps_only_the_first_message() {
+        let mut conversation = conversation_of(3);
+        
+        assert_eq!(conversation.truncate_to(0), 2);
+        assert_eq!(conversation.messages.len(), 1);
+        assert_eq!(conversation.messages[0].content, "message 0");
+    }
+
+    #[test]
+    fn ranks_titles_by_similarity_to_a_misspelled_query() {
+        let mut list = ConversationList::new();
+        for title in ["Rust borrow checker", "React hooks", "Python asyncio", "React router setup"] {
+            list.add_conversation(&Conversation::new(title.to_string()));
+        }
+        
+        let titles = |threshold| -> Vec<_> {
+            list.rank_by_title("raect hooks", threshold).into_iter().map(|(c, _)| c.title.as_str()).collect()
+        };
+        assert_eq!(titles(0.0), ["React hooks", "React router setup", "Rust borrow checker", "Python asyncio"]);
+        assert_eq!(titles(0.7), ["React hooks"]);
+    }
+
+    /// A directory under the system temp dir, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("ai-agent-test-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn loads_files_with_a_byte_order_mark() {
+        let dir = TempDir::new();
+        let conversation = conversation_of(2);
+        let path = dir.0.join("conversation.json");
+        let mut bytes = b"\xEF\xBB\xBF".to_vec();
+        bytes.extend(serde_json::to_vec(&conversation).unwrap());
+        fs::write(&path, &bytes).unwrap();
+        
+        let loaded = Conversation::load_from_file(&path).unwrap();
+        assert_eq!(loaded.id, conversation.id);
+        assert_eq!(loaded.messages.len(), 2);
+        
+        let mut list = ConversationList::new();
+        list.add_conversation(&conversation);
+        let list_path = dir.0.join("conversations.json");
+        let mut bytes = b"\xEF\xBB\xBF".to_vec();
+        bytes.extend(serde_json::to_vec(&list).unwrap());
+        fs::write(&list_path, &bytes).unwrap();
+        
+        assert_eq!(ConversationList::load_from_file(&list_path).unwrap().conversations.len(), 1);
+    }
+
+    #[test]
+    fn loads_files_with_invalid_utf8() {
+        let dir = TempDir::new();
+        let mut conversation = conversation_of(1);
+        conversation.title = "MARKER".to_string();
+        let path = dir.0.join("conversation.json");
+        let json = serde_json::to_vec(&conversation).unwrap();
+        let start = json.windows(6).position(|w| w == b"MARKER").unwrap();
+        let mut bytes = json[..start].to_vec();
+        bytes.extend(b"caf\xE9");
+        bytes.extend(&json[start + 6..]);
+        fs::write(&path, &bytes).unwrap();
+        
+        let loaded = Conversation::load_from_file(&path).unwrap();
+        assert_eq!(loaded.title, "caf\u{FFFD}");
+        assert_eq!(loaded.messages.len(), 1);
+    }
+
+    #[test]
+    fn backs_up_a_corrupt_index_without_replacing_an_earlier_backup() {
+        let dir = TempDir::new();
+        let path = dir.0.join("conversations.json");
+        fs::write(dir.0.join("conversations.json.bak"), "earlier backup").unwrap();
+        fs::write(&path, "{ truncated").unwrap();
+        
+        let list = ConversationList::load_from_file(&path).unwrap();
+        assert!(list.conversations.is_empty());
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(dir.0.join("conversations.json.bak")).unwrap(), "earlier backup");
+        let backups: Vec<_> = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("conversations.json.") && name.ends_with(".bak") && name != "conversations.json.bak")
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(fs::read_to_string(dir.0.join(&backups[0])).unwrap(), "{ truncated");
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::util::truncate_chars;
+use super::conversation::{write_atomically, Conversation, ConversationList, Role};
+use super::openai::OpenAIAgent;
+
+/// Longest message text sent for embedding; embedding models reject very long inputs.
+const MAX_EMBEDDED_CHARS: usize = 8000;
+
+/// The vectors of one conversation's messages, stored in `<id>.embeddings.json`
+/// next to the conversation and rebuilt when its message count or the model changes.
+#[derive(Serialize, Deserialize)]
+struct EmbeddingCache {
+    model: String,
+    message_count: usize,
+    /// Message index and vector, for the messages worth searching
+    vectors: Vec<(usize, Vec<f32>)>,
+}
+
+/// A message that matched a semantic search.
+pub struct SearchHit {
+    pub conversation_id: String,
+    pub title: String,
+    /// Position of the message in its conversation
+    pub index: usize,
+    pub role: Role,
+    pub content: String,
+    /// Cosine similarity to the query
+    pub score: f32,
+}
+
+/// The cache file holding the embeddings of conversation `id`.
+pub fn embeddings_path(history_path: &Path, id: &str) -> PathBuf {
+    history_path.join(format!("{}.embeddings.json", id))
+}
+
+/// Embeds `query` and returns the `top_k` messages across all saved conversations
+/// that are most similar to it, best first. Conversations whose cached embeddings
+/// are missing or stale are embedded first.
+pub async fn semantic_search(agent: &OpenAIAgent, config: &Config, query: &str, top_k: usize) -> Result<Vec<SearchHit>> {
+    let list = ConversationList::load_from_file(&config.history_path.join("conversations.json"))?;
+    let query = agent.embed(&[query.to_string()]).await?.remove(0);
+    
+    let mut hits = Vec::new();
+    for summary in &list.conversations {
+        let path = config.history_path.join(format!("{}.json", summary.id));
+        let conversation = match Conversation::load_from_file(&path) {
+            Ok(conversation) => conversation,
+            Err(e) => {
+                warn!("Skipping conversation {} in search: {}", summary.id, e);
+                continue;
+            }
+        };
+        
+        let cache = cached_embeddings(agent, config, &conversation).await?;
+        for (index, vector) in cache.vectors {
+            let Some(message) = conversation.messages.get(index) else {
+                continue;
+            };
+            hits.push(SearchHit {
+                conversation_id: conversation.id.clone(),
+                title: conversation.title.clone(),
+                index,
+                role: message.role.clone(),
+                content: message.content.clone(),
+                score: cosine_similarity(&query, &vector),
+            });
+        }
+    }
+    
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(top_k);
+    Ok(hits)
+}
+
+/// Loads the conversation's embeddings from its cache, embedding it again when the
+/// cache is missing, unreadable or out of date.
+async fn cached_embeddings(agent: &OpenAIAgent, config: &Config, conversation: &Conversation) -> Result<EmbeddingCache> {
+    let path = embeddings_path(&config.history_path, &conversation.id);
+    let cached = fs::read_to_string(&path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<EmbeddingCache>(&json).ok())
+        .filter(|cache| cache.model == config.embedding_model && cache.message_count == conversation.messages.len());
+    if let Some(cache) = cached {
+        return Ok(cache);
+    }
+    
+    let (indices, texts): (Vec<usize>, Vec<String>) = conversation
+        .messages
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| !matches!(m.role, Role::System) && !m.content.trim().is_empty())
+        .map(|(index, m)| (index, truncate_chars(&m.content, MAX_EMBEDDED_CHARS).to_string()))
+        .unzip();
+    let vectors = if texts.is_empty() { Vec::new() } else { agent.embed(&texts).await? };
+    
+    let cache = EmbeddingCache {
+        model: config.embedding_model.clone(),
+        message_count: conversation.messages.len(),
+        vectors: indices.into_iter().zip(vectors).collect(),
+    };
+    // A cache that can't be written only costs another embedding round next time
+    if let Err(e) = write_atomically(&path, serde_json::to_string(&cache)?.as_bytes()) {
+        warn!("Failed to cache embeddings in {}: {}", path.display(), e);
+    }
+    Ok(cache)
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
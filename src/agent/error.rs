@@ -0,0 +1,55 @@
+use reqwest::StatusCode;
+use serde_json::Value;
+use thiserror::Error;
+
+/// An error status from the API, with the message taken from the standard
+/// `{"error": {"message", "type", "code"}}` envelope when the body has one.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("authentication failed ({status}): {message}\nHint: check your API key (OPENAI_API_KEY, the config file or `ai-agent set-key`)")]
+    Unauthorized { status: u16, message: String },
+    #[error("rate limit exceeded ({status}): {message}")]
+    RateLimited { status: u16, message: String },
+    #[error("API error ({status}{}): {message}", code.as_deref().map(|code| format!(", {}", code)).unwrap_or_default())]
+    Status { status: u16, code: Option<String>, message: String },
+}
+
+impl ApiError {
+    /// Classifies an error response by its status and extracts a readable message from its body.
+    pub fn from_response(status: StatusCode, body: &str) -> Self {
+        let (message, code) = parse_error_body(body).unwrap_or_else(|| {
+            let body = body.trim();
+            let message = if body.is_empty() {
+                status.canonical_reason().unwrap_or("no details").to_string()
+            } else {
+                body.to_string()
+            };
+            (message, None)
+        });
+        
+        let status = status.as_u16();
+        match status {
+            401 | 403 => ApiError::Unauthorized { status, message },
+            429 => ApiError::RateLimited { status, message },
+            _ => ApiError::Status { status, code, message },
+        }
+    }
+}
+
+/// The message and code (or, failing that, the type) of an error envelope.
+/// Some providers send the error as a bare string instead of an object.
+fn parse_error_body(body: &str) -> Option<(String, Option<String>)> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let error = value.get("error")?;
+    if let Some(message) = error.as_str() {
+        return Some((message.to_string(), None));
+    }
+    
+    let message = error["message"].as_str().filter(|m| !m.trim().is_empty())?.to_string();
+    let code = match &error["code"] {
+        Value::String(code) => Some(code.clone()),
+        Value::Number(code) => Some(code.to_string()),
+        _ => error["type"].as_str().map(str::to_string),
+    };
+    Some((message, code))
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::time;
+use uuid::Uuid;
+
+use crate::util::estimate_tokens;
+use super::error::ApiError;
+
+/// Produces an OpenAI-shaped chat completion without touching the network: the last
+/// user message is echoed back after a short random delay, and requests fail at
+/// `error_rate` with a rate-limit error so retry handling can be exercised.
+pub async fn complete(model: &str, messages: &[Value], error_rate: f32) -> Result<Value> {
+    let roll = Uuid::new_v4().as_u128();
+    time::sleep(Duration::from_millis(300 + (roll % 600) as u64)).await;
+    
+    if ((roll >> 64) % 1000) as f32 / 1000.0 < error_rate {
+        return Err(ApiError::RateLimited {
+            status: 429,
+            message: "simulated by the mock provider".to_string(),
+        }.into());
+    }
+    
+    let prompt = messages
+        .iter()
+        .rev()
+        .find(|m| m["role"] == "user")
+        .and_then(|m| m["content"].as_str())
+        .unwrap_or_default();
+    let content = format!("(mock) You said: {}", prompt);
+    
+    let prompt_tokens: usize = messages
+        .iter()
+        .filter_map(|m| m["content"].as_str())
+        .map(estimate_tokens)
+        .sum();
+    let completion_tokens = estimate_tokens(&content);
+    
+    Ok(json!({
+        "id": format!("mock-{}", Uuid::new_v4()),
+        "object": "chat.completion",
+        "created": chrono::Utc::now().timestamp(),
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": "stop"
+        }],
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens
+        }
+    }))
+}
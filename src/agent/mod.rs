@@ -1,5 +1,17 @@
+mod anthropic;
 mod conversation;
+mod embeddings;
+mod error;
+mod mock;
 mod openai;
+mod postprocess;
+mod roles;
+mod sse;
+mod tokenizer;
 
-pub use conversation::{Conversation, ConversationList, Message, Role};
-pub use openai::OpenAIAgent; 
\ No newline at end of file
+pub(crate) use conversation::write_atomically;
+pub use conversation::{ContentPart, Conversation, ConversationList, ConversationSummary, Message, Role, StreamStats, TokenUsage};
+pub use embeddings::{embeddings_path, semantic_search};
+pub use openai::OpenAIAgent;
+pub use postprocess::PostProcessor;
+pub use tokenizer::count_tokens;
\ No newline at end of file
@@ -1,29 +1,88 @@
 use anyhow::{Result, anyhow};
+use chrono::Local;
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tracing::{debug, info};
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
-use crate::config::Config;
+use crate::config::{ApiFormat, Config, Provider, ProviderKind};
 use crate::mcp;
-use super::conversation::{Conversation, Message};
+use crate::util::{truncate_chars, truncate_with_marker};
+use super::conversation::{Conversation, Message, ResponseMetadata, StreamStats, TokenUsage};
+use super::sse::{SseEvent, SseParser};
+use super::error::ApiError;
+use super::{anthropic, mock, postprocess, roles};
+
+const SUMMARY_PROMPT: &str = "Summarize the following conversation concisely, keeping decisions, code and facts \
+    that later questions may build on.";
+
+/// Appended to the answer when the model still asks for tools after `max_tool_iterations` rounds.
+const TOOL_CAP_NOTE: &str = "(reached max tool iterations — answer may be incomplete)";
+
+const TITLE_PROMPT: &str = "Write a title of at most six words for the following conversation. \
+    Reply with the title only, without quotes.";
+
+/// The answer given in place of a response when requests are only printed.
+const DRY_RUN_REPLY: &str = "[dry-run: no request sent]";
+
+/// Inputs sent per embeddings request.
+const EMBEDDING_BATCH_SIZE: usize = 100;
 
 #[derive(Clone, Debug)]
 pub struct OpenAIAgent {
     config: Config,
     client: Client,
+    /// The most recent raw API response, kept for debugging
+    last_response: Arc<Mutex<Option<Value>>>,
+    /// The most recent request, kept so it can be sent again unchanged
+    last_request: Arc<Mutex<Option<ChatCompletionRequest>>>,
+    /// Tokens spent by every request of this session, including titles and summaries
+    session_usage: Arc<Mutex<TokenUsage>>,
+    /// Usage of the stream that finished last, until `streamed_message` claims it
+    stream_usage: Arc<Mutex<Option<TokenUsage>>>,
+    /// Requests sent again for the answer in progress, recorded in its metadata
+    retries: Arc<Mutex<u32>>,
+    /// Tool calls and results of the answer in progress, until `take_tool_turns` claims them
+    tool_turns: Arc<Mutex<Vec<Message>>>,
+    /// Told about each tool call just before it runs
+    on_tool_call: Option<ToolCallHook>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+type ToolCallFn = dyn Fn(&str, &Value) + Send + Sync;
+
+/// A callback receiving the function name and arguments of each tool call the model makes.
+#[derive(Clone)]
+struct ToolCallHook(Arc<ToolCallFn>);
+
+impl std::fmt::Debug for ToolCallHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ToolCallHook")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ChatCompletionRequest {
     model: String,
     messages: Vec<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     tools: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -71,177 +130,946 @@ struct ChatCompletionUsage {
     total_tokens: u32,
 }
 
+impl From<&ChatCompletionUsage> for TokenUsage {
+    fn from(usage: &ChatCompletionUsage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens.into(),
+            completion_tokens: usage.completion_tokens.into(),
+        }
+    }
+}
+
+/// Progress of a streamed answer, threaded through [`OpenAIAgent::chat_stream`].
+enum StreamState {
+    Start,
+    Reading {
+        reader: Box<StreamReader>,
+        /// The request behind the current turn, grown by tool results between turns
+        request: Box<ChatCompletionRequest>,
+        iteration: usize,
+        /// Whether earlier turns already produced text
+        streamed: bool,
+        /// Tokens spent by the turns finished so far
+        usage: Option<TokenUsage>,
+    },
+    Done,
+}
+
+/// A tool call assembled from the fragments spread over stream chunks.
+#[derive(Debug, Default)]
+struct PartialToolCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+/// Collects a streamed response: content pieces waiting to be yielded, plus what
+/// is needed to assemble tool calls and a complete response for `!raw`.
+struct StreamReader {
+    api_format: ApiFormat,
+    body: Option<BoxStream<'static, reqwest::Result<Vec<u8>>>>,
+    parser: SseParser,
+    pending: VecDeque<String>,
+    /// Put before the first piece of text, to separate it from an earlier turn's
+    prefix: Option<&'static str>,
+    finished: bool,
+    content: String,
+    tool_calls: Vec<PartialToolCall>,
+    id: Option<String>,
+    model: Option<String>,
+    finish_reason: Option<String>,
+    usage: Option<Value>,
+}
+
+impl StreamReader {
+    fn new(api_format: ApiFormat, body: Option<BoxStream<'static, reqwest::Result<Vec<u8>>>>) -> Self {
+        Self {
+            api_format,
+            body,
+            parser: SseParser::default(),
+            pending: VecDeque::new(),
+            prefix: None,
+            finished: false,
+            content: String::new(),
+            tool_calls: Vec::new(),
+            id: None,
+            model: None,
+            finish_reason: None,
+            usage: None,
+        }
+    }
+
+    /// Parses the chunks completed by `bytes`, queueing their content.
+    fn feed(&mut self, bytes: &[u8]) -> Result<()> {
+        for event in self.parser.feed(bytes) {
+            let data = match event {
+                SseEvent::Data(data) => data,
+                SseEvent::Done => {
+                    self.finished = true;
+                    continue;
+                },
+            };
+            let chunk: Value = serde_json::from_str(&data).map_err(|e| anyhow!(
+                "Stream chunk is not valid JSON ({}): {}",
+                e,
+                truncate_chars(&data, 200)
+            ))?;
+            let chunk = match self.api_format {
+                ApiFormat::ChatCompletions => chunk,
+                ApiFormat::AnthropicMessages => match anthropic::to_chat_completion_chunk(&chunk) {
+                    Some(chunk) => chunk,
+                    None => continue,
+                },
+            };
+            if let Some(error) = chunk.get("error") {
+                return Err(anyhow!("API error in stream: {}", error));
+            }
+            
+            self.id = self.id.take().or_else(|| chunk["id"].as_str().map(str::to_string));
+            self.model = self.model.take().or_else(|| chunk["model"].as_str().map(str::to_string));
+            // Usage may come in parts (Anthropic reports input and output tokens separately)
+            if let Some(usage) = chunk["usage"].as_object() {
+                let merged = self.usage.get_or_insert_with(|| json!({}));
+                for (name, count) in usage.iter().filter(|(_, count)| count.as_u64().is_some_and(|c| c > 0)) {
+                    merged[name] = count.clone();
+                }
+            }
+            
+            let choice = &chunk["choices"][0];
+            if let Some(reason) = choice["finish_reason"].as_str() {
+                self.finish_reason = Some(reason.to_string());
+            }
+            if let Some(content) = choice["delta"]["content"].as_str().filter(|c| !c.is_empty()) {
+                self.content.push_str(content);
+                self.pending.push_back(match self.prefix.take() {
+                    Some(prefix) => format!("{}{}", prefix, content),
+                    None => content.to_string(),
+                });
+            }
+            // Tool calls arrive in fragments keyed by their index
+            for fragment in choice["delta"]["tool_calls"].as_array().into_iter().flatten() {
+                let index = fragment["index"].as_u64().unwrap_or(0) as usize;
+                if self.tool_calls.len() <= index {
+                    self.tool_calls.resize_with(index + 1, PartialToolCall::default);
+                }
+                let call = &mut self.tool_calls[index];
+                if let Some(id) = fragment["id"].as_str() {
+                    call.id = id.to_string();
+                }
+                if let Some(name) = fragment["function"]["name"].as_str() {
+                    call.name.push_str(name);
+                }
+                if let Some(arguments) = fragment["function"]["arguments"].as_str() {
+                    call.arguments.push_str(arguments);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn tool_calls(&self) -> Vec<ToolCall> {
+        self.tool_calls
+            .iter()
+            .filter(|call| !call.name.is_empty())
+            .map(|call| ToolCall {
+                id: call.id.clone(),
+                call_type: "function".to_string(),
+                function: FunctionCall {
+                    name: call.name.clone(),
+                    arguments: if call.arguments.is_empty() { "{}".to_string() } else { call.arguments.clone() },
+                },
+            })
+            .collect()
+    }
+
+    /// The token counts reported in the stream, if any.
+    fn token_usage(&self) -> Option<TokenUsage> {
+        let usage = self.usage.as_ref()?;
+        Some(TokenUsage {
+            prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or(0),
+            completion_tokens: usage["completion_tokens"].as_u64().unwrap_or(0),
+        })
+    }
+
+    /// The streamed chunks put back together in the shape of a non-streamed response.
+    fn assembled_response(&self) -> Value {
+        let mut message = json!({ "role": "assistant", "content": self.content });
+        let tool_calls = self.tool_calls();
+        if !tool_calls.is_empty() {
+            message["tool_calls"] = json!(tool_calls);
+        }
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "model": self.model,
+            "choices": [{ "index": 0, "message": message, "finish_reason": self.finish_reason }],
+            "usage": self.usage,
+        })
+    }
+}
+
 impl OpenAIAgent {
     pub fn new(config: Config) -> Self {
         Self {
+            client: config.http_client(),
             config,
-            client: Client::new(),
+            last_response: Arc::new(Mutex::new(None)),
+            last_request: Arc::new(Mutex::new(None)),
+            session_usage: Arc::new(Mutex::new(TokenUsage::default())),
+            stream_usage: Arc::new(Mutex::new(None)),
+            retries: Arc::new(Mutex::new(0)),
+            tool_turns: Arc::new(Mutex::new(Vec::new())),
+            on_tool_call: None,
         }
     }
 
+    /// Calls `hook` with the function name and arguments of every tool call before it runs,
+    /// so the user can see what the model decided to do.
+    pub fn on_tool_call(&mut self, hook: impl Fn(&str, &Value) + Send + Sync + 'static) {
+        self.on_tool_call = Some(ToolCallHook(Arc::new(hook)));
+    }
+
+    /// Applies new settings while keeping the last request and response.
+    pub fn reconfigure(&mut self, config: Config) {
+        self.client = config.http_client();
+        self.config = config;
+    }
+
+    /// The last raw response received from the API, if any.
+    pub fn last_response(&self) -> Option<Value> {
+        self.last_response.lock().unwrap().clone()
+    }
+
+    /// Tokens spent by all requests of this session that reported usage.
+    pub fn session_usage(&self) -> TokenUsage {
+        *self.session_usage.lock().unwrap()
+    }
+
+    /// Adds one response's usage to the session total and to `total`.
+    fn record_usage(&self, total: &mut Option<TokenUsage>, usage: TokenUsage) {
+        self.session_usage.lock().unwrap().add(usage);
+        total.get_or_insert_with(TokenUsage::default).add(usage);
+    }
+
+    /// Asks the model for a compact summary of a conversation transcript.
+    pub async fn summarize(&self, transcript: &str) -> Result<String> {
+        let mut request = Conversation::new("Summary".to_string());
+        request.add_message(Message::system(SUMMARY_PROMPT.to_string()));
+        request.add_message(Message::user(transcript.to_string()));
+        Ok(self.chat(&request).await?.content)
+    }
+
+    /// Asks the model for a short title describing the start of `conversation`.
+    pub async fn suggest_title(&self, conversation: &Conversation) -> Result<String> {
+        let opening = truncate_chars(&conversation.transcript(), 2000).to_string();
+        let mut request = Conversation::new("Title".to_string());
+        request.add_message(Message::system(TITLE_PROMPT.to_string()));
+        request.add_message(Message::user(opening));
+        
+        let title = self.chat(&request).await?.content;
+        let first_line = title.trim().lines().next().unwrap_or_default();
+        Ok(truncate_chars(first_line.trim().trim_matches('"'), 60).to_string())
+    }
+
+    /// Folds the oldest messages of `conversation` into a summary, keeping the
+    /// `keep_recent` newest verbatim. Returns how many messages were summarized.
+    pub async fn summarize_history(&self, conversation: &mut Conversation, keep_recent: usize) -> Result<usize> {
+        let Some((count, transcript)) = conversation.summarizable(keep_recent) else {
+            return Ok(0);
+        };
+        
+        let summary = self.summarize(&transcript).await?;
+        conversation.replace_oldest_with_summary(count, summary);
+        Ok(count)
+    }
+
+    /// Embeds `inputs` with the configured embedding model, returning one vector per input in order.
+    pub async fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>> {
+        let provider = self.config.provider();
+        if provider.api_format != ApiFormat::ChatCompletions || provider.kind == ProviderKind::Mock {
+            return Err(anyhow!("{} has no embeddings endpoint", provider.name));
+        }
+        
+        let url = format!("{}/embeddings", self.config.openai_api_base_url);
+        let mut vectors = Vec::with_capacity(inputs.len());
+        for batch in inputs.chunks(EMBEDDING_BATCH_SIZE) {
+            let body = json!({ "model": self.config.embedding_model, "input": batch });
+            let response: Value = self.post_json(provider, &url, &body, false).await?.json().await?;
+            
+            let mut data: Vec<&Value> = response["data"].as_array().map(|d| d.iter().collect()).unwrap_or_default();
+            if data.len() != batch.len() {
+                return Err(anyhow!("Expected {} embeddings, got {}", batch.len(), data.len()));
+            }
+            data.sort_by_key(|item| item["index"].as_u64());
+            for item in data {
+                let vector: Vec<f32> = serde_json::from_value(item["embedding"].clone())
+                    .map_err(|e| anyhow!("Malformed embedding in response: {}", e))?;
+                vectors.push(vector);
+            }
+        }
+        Ok(vectors)
+    }
+
     pub async fn chat(&self, conversation: &Conversation) -> Result<Message> {
-        // Ensure MCP server is running - but continue if it fails
-        let mcp_server_available = mcp::ensure_mcp_server_running(&self.config).await.is_ok();
+        let request = self.build_request(conversation, false).await?;
+        self.complete(request).await
+    }
+
+    /// Streams the answer to `conversation`, yielding content as the model writes it.
+    /// When a turn ends asking for tools, they are run and their results sent back in a
+    /// new request whose answer is streamed in turn, up to `max_tool_iterations` rounds.
+    /// Assemble the pieces with [`Self::streamed_message`].
+    pub fn chat_stream<'a>(&'a self, conversation: &'a Conversation) -> impl Stream<Item = Result<String>> + 'a {
+        stream::try_unfold(StreamState::Start, move |mut state| async move {
+            loop {
+                match state {
+                    StreamState::Start => {
+                        let request = Box::new(self.build_request(conversation, true).await?);
+                        *self.last_request.lock().unwrap() = Some((*request).clone());
+                        *self.stream_usage.lock().unwrap() = None;
+                        *self.retries.lock().unwrap() = 0;
+                        self.tool_turns.lock().unwrap().clear();
+                        if self.config.dry_run {
+                            self.print_dry_run(&request)?;
+                            return Ok(Some((DRY_RUN_REPLY.to_string(), StreamState::Done)));
+                        }
+                        let reader = Box::new(self.open_stream(&request).await?);
+                        state = StreamState::Reading { reader, request, iteration: 1, streamed: false, usage: None };
+                    },
+                    StreamState::Reading { mut reader, mut request, iteration, streamed, mut usage } => {
+                        if let Some(piece) = reader.pending.pop_front() {
+                            return Ok(Some((piece, StreamState::Reading { reader, request, iteration, streamed, usage })));
+                        }
+                        if reader.finished {
+                            *self.last_response.lock().unwrap() = Some(reader.assembled_response());
+                            if let Some(turn_usage) = reader.token_usage() {
+                                self.record_usage(&mut usage, turn_usage);
+                            }
+                            *self.stream_usage.lock().unwrap() = usage;
+                            let tool_calls = reader.tool_calls();
+                            if tool_calls.is_empty() {
+                                return Ok(None);
+                            }
+                            
+                            // Hand the tool results back and stream the model's next turn
+                            let max_iterations = self.config.max_tool_iterations.max(1);
+                            info!("Tool iteration {}/{}: running {} tool call(s)", iteration, max_iterations, tool_calls.len());
+                            let output = self.run_tool_calls(&mut request.messages, &reader.content, &tool_calls).await;
+                            let streamed = streamed || !reader.content.is_empty();
+                            if iteration >= max_iterations {
+                                warn!("Stopped after {} tool iterations without a final answer", max_iterations);
+                                let mut note = if streamed { "\n\n".to_string() } else { String::new() };
+                                if reader.content.is_empty() {
+                                    note.push_str(output.trim_end());
+                                    note.push_str("\n\n");
+                                }
+                                note.push_str(TOOL_CAP_NOTE);
+                                return Ok(Some((note, StreamState::Done)));
+                            }
+                            
+                            let mut next = self.open_stream(&request).await?;
+                            next.prefix = streamed.then_some("\n\n");
+                            state = StreamState::Reading { reader: Box::new(next), request, iteration: iteration + 1, streamed, usage };
+                            continue;
+                        }
+                        match reader.body.as_mut().map(|body| body.next()) {
+                            // A stalled stream must not hang the chat
+                            Some(next) => match tokio::time::timeout(self.config.request_timeout(), next).await {
+                                Ok(Some(bytes)) => reader.feed(&bytes?)?,
+                                Ok(None) => reader.finished = true,
+                                Err(_) => return Err(anyhow!(
+                                    "API stream stalled: no data for {} seconds",
+                                    self.config.request_timeout().as_secs()
+                                )),
+                            },
+                            None => reader.finished = true,
+                        }
+                        state = StreamState::Reading { reader, request, iteration, streamed, usage };
+                    },
+                    StreamState::Done => return Ok(None),
+                }
+            }
+        })
+    }
+
+    /// Builds the assistant message for a streamed answer from its assembled content
+    /// and the timings measured while it arrived.
+    pub fn streamed_message(&self, content: String, chunks: usize, first_token: Duration, total: Duration) -> Message {
+        let usage = self.stream_usage.lock().unwrap().take();
+        let mut message = self.finish(content, total, usage);
+        message.stream_stats = Some(StreamStats::new(chunks, first_token, total, &message.content));
+        message
+    }
+
+    /// Starts a streaming request. The mock provider has nothing to stream, so its
+    /// answer is split into words up front, and the reader is filled in as if the
+    /// whole response had been read so `!raw` and usage come out the same.
+    async fn open_stream(&self, request: &ChatCompletionRequest) -> Result<StreamReader> {
+        let provider = self.config.provider();
+        debug!("Sending streaming chat completion request to API: {}", self.loggable_request(request));
         
-        // Determine if we're using OpenAI, Ollama, Groq, or another provider
-        let is_ollama = self.config.openai_api_base_url.contains("ollama") ||
-                       self.config.openai_api_base_url.contains("localhost");
-        let is_groq = self.config.openai_api_base_url.contains("groq");
+        if provider.kind == ProviderKind::Mock {
+            let response = self.mock_complete(request).await?;
+            let choice = &response["choices"][0];
+            let content = choice["message"]["content"].as_str().unwrap_or_default();
+            let mut reader = StreamReader::new(provider.api_format, None);
+            reader.pending.extend(content.split_inclusive(' ').map(str::to_string));
+            reader.content = content.to_string();
+            reader.id = response["id"].as_str().map(str::to_string);
+            reader.model = response["model"].as_str().map(str::to_string);
+            reader.finish_reason = choice["finish_reason"].as_str().map(str::to_string);
+            reader.usage = Some(response["usage"].clone()).filter(Value::is_object);
+            reader.finished = true;
+            return Ok(reader);
+        }
+        
+        let response = self.post(provider, request).await?;
+        Ok(StreamReader::new(provider.api_format, Some(response.bytes_stream().map_ok(|bytes| bytes.to_vec()).boxed())))
+    }
+
+    /// Assembles the request for `conversation`, applying provider quirks and
+    /// parameters pinned to the conversation.
+    async fn build_request(&self, conversation: &Conversation, stream: bool) -> Result<ChatCompletionRequest> {
+        // Provider quirks (auth, temperature, tools) come from its descriptor
+        let provider = self.config.provider();
+        
+        // Ensure the MCP servers are running when their tools can be used - but continue if some fail
+        let tools_enabled = self.config.mcp_enabled && provider.supports_tools;
+        if tools_enabled {
+            if let Err(e) = mcp::ensure_mcp_servers_running(&self.config).await {
+                warn!("Some MCP servers are unavailable: {}", e);
+            }
+        }
+        
+        // The oldest messages are left out once the history outgrows the context budget
+        let mut messages = conversation.to_openai_messages_truncated(&self.config.openai_api_model, self.config.context_budget_tokens());
+        
+        // Inject the context note after the leading system messages; it is never persisted
+        // so values like the date stay current
+        if let Some(template) = &self.config.context_template {
+            let position = messages.iter().take_while(|m| m["role"] == "system").count();
+            messages.insert(position, json!({ "role": "system", "content": render_context_note(template) }));
+        }
+        
+        // Strict backends reject conversations that don't alternate starting with a user turn
+        if self.config.normalize_roles {
+            messages = roles::normalize_roles(messages)?;
+        }
+        
+        // Parameters pinned to the conversation win over the global config
+        let params = conversation.params.clone().unwrap_or_default();
         
         // Create the request to API
         let request = ChatCompletionRequest {
             model: self.config.openai_api_model.clone(),
-            messages: conversation.to_openai_messages(),
-            temperature: if is_ollama { None } else { Some(0.7) },
-            stream: if is_ollama { None } else { Some(false) },
-            tools: if is_ollama || is_groq || !mcp_server_available { None } else { Some(self.get_tools()) },
+            messages,
+            temperature: if provider.supports_temperature { params.temperature.or(self.config.temperature) } else { None },
+            top_p: params.top_p.or(self.config.top_p),
+            max_tokens: params.max_tokens.or(self.config.max_tokens),
+            stream: if stream { Some(true) } else if provider.supports_stream_flag { Some(false) } else { None },
+            stream_options: (stream && provider.supports_stream_usage).then(|| json!({ "include_usage": true })),
+            tools: Some(self.get_tools()).filter(|tools| tools_enabled && !tools.is_empty()),
+            reasoning_effort: if self.config.is_reasoning_model() { self.config.reasoning_effort.clone() } else { None },
         };
         
-        debug!("Sending chat completion request to API: {:?}", request);
+        Ok(request)
+    }
+
+    /// Sends the last request again exactly as it was, whatever has changed since.
+    pub async fn retry_last(&self) -> Result<Message> {
+        let request = self.last_request.lock().unwrap().clone();
+        match request {
+            Some(mut request) => {
+                // A streamed request is replayed as a plain one
+                if request.stream == Some(true) {
+                    request.stream = self.config.provider().supports_stream_flag.then_some(false);
+                    request.stream_options = None;
+                }
+                self.complete(request).await
+            },
+            None => Err(anyhow!("No request has been sent yet in this session")),
+        }
+    }
+
+    /// Sends a prepared request and turns the response into an assistant message,
+    /// running any tool calls it asks for.
+    async fn complete(&self, mut request: ChatCompletionRequest) -> Result<Message> {
+        let provider = self.config.provider();
+        *self.last_request.lock().unwrap() = Some(request.clone());
+        *self.retries.lock().unwrap() = 0;
+        self.tool_turns.lock().unwrap().clear();
+        if self.config.dry_run {
+            self.print_dry_run(&request)?;
+            return Ok(self.finish(DRY_RUN_REPLY.to_string(), Duration::ZERO, None));
+        }
         
-        // Make the API request
-        let url = format!("{}/chat/completions", self.config.openai_api_base_url);
-        let mut req_builder = self.client.post(&url)
-            .header("Content-Type", "application/json");
+        let started = Instant::now();
+        let max_iterations = self.config.max_tool_iterations.max(1);
+        let mut tool_output = String::new();
+        let mut usage = None;
+        for iteration in 1..=max_iterations {
+            debug!("Sending chat completion request to API: {}", self.loggable_request(&request));
+            let raw_response = if provider.kind == ProviderKind::Mock {
+                self.mock_complete(&request).await?
+            } else {
+                self.send(provider, &request).await?
+            };
+            *self.last_response.lock().unwrap() = Some(raw_response.clone());
+            let response_json = ChatCompletionResponse::deserialize(&raw_response).map_err(|e| anyhow!(
+                "API response is not a chat completion ({}): {}",
+                e,
+                truncate_chars(&raw_response.to_string(), 200)
+            ))?;
+            if self.config.log_prompts {
+                debug!("Received chat completion response: {:?}", response_json);
+            }
+            if let Some(response_usage) = &response_json.usage {
+                self.record_usage(&mut usage, response_usage.into());
+            }
             
-        // Add authorization header unless we're using Ollama (which doesn't need it)
-        if !is_ollama {
-            req_builder = req_builder.header("Authorization", format!("Bearer {}", self.config.openai_api_key));
+            // Process the response
+            let Some(choice) = response_json.choices.first() else {
+                return Err(anyhow!("No choices in API response"));
+            };
+            let content = choice.message.content.clone().unwrap_or_default();
+            let tool_calls = match &choice.message.tool_calls {
+                Some(tool_calls) if !tool_calls.is_empty() => tool_calls,
+                _ => return Ok(self.finish(content, started.elapsed(), usage)),
+            };
+            
+            // Hand the tool results back so the model can answer from them
+            info!("Tool iteration {}/{}: running {} tool call(s)", iteration, max_iterations, tool_calls.len());
+            tool_output = self.run_tool_calls(&mut request.messages, &content, tool_calls).await;
         }
         
-        let response = req_builder
-            .json(&request)
-            .send()
-            .await?;
+        warn!("Stopped after {} tool iterations without a final answer", max_iterations);
+        Ok(self.finish(format!("{}\n\n{}", tool_output.trim_end(), TOOL_CAP_NOTE), started.elapsed(), usage))
+    }
+
+    /// Runs the tool calls the model asked for, appending the assistant's call and one
+    /// `tool` message per result to `messages`, and keeping both for `take_tool_turns`.
+    /// Returns the combined tool output.
+    async fn run_tool_calls(&self, messages: &mut Vec<Value>, content: &str, tool_calls: &[ToolCall]) -> String {
+        let mut turns = vec![Message::tool_request(content.to_string(), tool_calls.iter().map(|call| json!(call)).collect())];
         
-        // Handle the response
-        let status = response.status();
-        if !status.is_success() {
-            let error_text = response.text().await?;
-            return Err(anyhow!("API error: {} - {}", status, error_text));
+        let mut outputs = String::new();
+        for tool_call in tool_calls {
+            let output = self.run_tool(tool_call).await;
+            outputs.push_str(&output);
+            outputs.push('\n');
+            turns.push(Message::tool(tool_call.id.clone(), tool_call.function.name.clone(), output));
         }
         
-        let response_json: ChatCompletionResponse = response.json().await?;
-        debug!("Received chat completion response: {:?}", response_json);
+        messages.extend(turns.iter().map(Message::to_openai_message));
+        self.tool_turns.lock().unwrap().extend(turns);
+        outputs
+    }
+
+    /// The assistant tool calls and tool results behind the last answer, in order, so
+    /// they can be added to the conversation before it.
+    pub fn take_tool_turns(&self) -> Vec<Message> {
+        std::mem::take(&mut *self.tool_turns.lock().unwrap())
+    }
+
+    /// Runs one tool call and describes its result for the model. Failures are
+    /// reported in the result so the model can recover from them.
+    async fn run_tool(&self, tool_call: &ToolCall) -> String {
+        let function_name = &tool_call.function.name;
+        let arguments: Value = match serde_json::from_str(&tool_call.function.arguments) {
+            Ok(arguments) => arguments,
+            Err(e) => return format!("Invalid arguments for {}: {}", function_name, e),
+        };
+        if let Some(ToolCallHook(hook)) = &self.on_tool_call {
+            hook(function_name, &arguments);
+        }
         
-        // Process the response
-        if let Some(choice) = response_json.choices.first() {
-            let content = if let Some(tool_calls) = &choice.message.tool_calls {
-                // Handle tool calls
-                let mut result = String::new();
-                
-                for tool_call in tool_calls {
-                    if tool_call.call_type == "function" {
-                        let function_name = &tool_call.function.name;
-                        let arguments: Value = serde_json::from_str(&tool_call.function.arguments)?;
-                        
-                        match function_name.as_str() {
-                            "mcp_context7_resolve_library_id" => {
-                                if let Some(library_name) = arguments.get("libraryName").and_then(|v| v.as_str()) {
-                                    info!("Resolving library ID for: {}", library_name);
-                                    match mcp::resolve_library_id(library_name.to_string()).await {
-                                        Ok(library_id) => {
-                                            result.push_str(&format!("Library ID for '{}' is: {}\n", library_name, library_id));
-                                        },
-                                        Err(e) => {
-                                            result.push_str(&format!("Failed to resolve library ID for '{}': {}\n", library_name, e));
-                                        }
-                                    }
-                                }
-                            },
-                            "mcp_context7_get_library_docs" => {
-                                if let Some(library_id) = arguments.get("context7CompatibleLibraryID").and_then(|v| v.as_str()) {
-                                    let tokens = arguments.get("tokens").and_then(|v| v.as_u64()).map(|v| v as u32);
-                                    let topic = arguments.get("topic").and_then(|v| v.as_str()).map(|v| v.to_string());
-                                    
-                                    info!("Getting library docs for: {}", library_id);
-                                    match mcp::get_library_docs(library_id.to_string(), tokens, topic).await {
-                                        Ok(docs) => {
-                                            // Truncate if too long for readability
-                                            let docs_preview = if docs.len() > 500 {
-                                                format!("{}... (truncated, {} total characters)", &docs[..500], docs.len())
-                                            } else {
-                                                docs.clone()
-                                            };
-                                            
-                                            result.push_str(&format!("Documentation for '{}':\n{}\n", library_id, docs_preview));
-                                            
-                                            // Actually add the full documentation
-                                            let full_response = format!("Based on the documentation for '{}':\n\n{}", library_id, docs);
-                                            return Ok(Message::assistant(full_response));
-                                        },
-                                        Err(e) => {
-                                            result.push_str(&format!("Failed to get documentation for '{}': {}\n", library_id, e));
-                                        }
-                                    }
-                                }
-                            },
-                            _ => {
-                                result.push_str(&format!("Unsupported tool call: {}\n", function_name));
-                            }
-                        }
-                    }
+        match function_name.as_str() {
+            "mcp_context7_resolve_library_id" => {
+                let Some(library_name) = arguments.get("libraryName").and_then(|v| v.as_str()) else {
+                    return "Missing required argument: libraryName".to_string();
+                };
+                info!("Resolving library ID for: {}", library_name);
+                match mcp::resolve_library_id(library_name.to_string()).await {
+                    Ok(matches) => format!(
+                        "Context7 libraries matching '{}':\n\n{}",
+                        library_name,
+                        truncate_with_marker(&matches, self.config.max_tool_output_chars)
+                    ),
+                    Err(e) => format!("Failed to resolve library ID for '{}': {}", library_name, e),
                 }
+            },
+            "mcp_context7_get_library_docs" => {
+                let Some(library_id) = arguments.get("context7CompatibleLibraryID").and_then(|v| v.as_str()) else {
+                    return "Missing required argument: context7CompatibleLibraryID".to_string();
+                };
+                let tokens = arguments.get("tokens").and_then(|v| v.as_u64()).map(|v| v as u32);
+                let topic = arguments.get("topic").and_then(|v| v.as_str()).map(|v| v.to_string());
                 
-                if result.is_empty() && choice.message.content.is_some() {
-                    choice.message.content.clone().unwrap_or_default()
-                } else {
-                    result
+                info!("Getting library docs for: {}", library_id);
+                match mcp::get_library_docs(library_id.to_string(), tokens, topic).await {
+                    // Capped so one fetch can't overflow the context window
+                    Ok(docs) => format!(
+                        "Documentation for '{}':\n\n{}",
+                        library_id,
+                        truncate_with_marker(&docs, self.config.max_tool_output_chars)
+                    ),
+                    Err(e) => format!("Failed to get documentation for '{}': {}", library_id, e),
                 }
-            } else {
-                choice.message.content.clone().unwrap_or_default()
+            },
+            _ => {
+                let Some(tool) = mcp::mcp_tools().into_iter().find(|tool| mcp_function_name(tool) == *function_name) else {
+                    return format!("Unsupported tool call: {}", function_name);
+                };
+                info!("Calling {} on MCP server {}", tool.name, tool.server);
+                match mcp::call_tool(&tool.server, &tool.name, arguments).await {
+                    Ok(output) => truncate_with_marker(&output, self.config.max_tool_output_chars),
+                    Err(e) => format!("Tool {} failed: {}", function_name, e),
+                }
+            },
+        }
+    }
+
+    /// Posts the request to the provider's chat completions endpoint and returns the raw JSON response.
+    async fn send(&self, provider: &Provider, request: &ChatCompletionRequest) -> Result<Value> {
+        let response = self.post(provider, request).await?;
+        
+        // Proxies and captive portals answer with HTML, sometimes even with a 200
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("an unknown content type")
+            .to_string();
+        let body = response.text().await?;
+        let json: Value = serde_json::from_str(&body).map_err(|e| anyhow!(
+            "API returned a response that is not valid JSON ({}): {}\nHint: expected JSON, got {} - is the base URL {} correct?",
+            e,
+            truncate_chars(body.trim(), 200),
+            content_type,
+            self.config.openai_api_base_url
+        ))?;
+        
+        match provider.api_format {
+            ApiFormat::ChatCompletions => Ok(json),
+            ApiFormat::AnthropicMessages => anthropic::to_chat_completion(json),
+        }
+    }
+
+    /// Posts the request to the chat completions endpoint, turning error statuses into errors.
+    /// Rate limits and server errors are retried with exponential backoff, honoring
+    /// `Retry-After`; other errors fail at once.
+    async fn post(&self, provider: &Provider, request: &ChatCompletionRequest) -> Result<reqwest::Response> {
+        let (url, body) = self.request_body(provider, request)?;
+        self.post_json(provider, &url, &body, request.stream == Some(true)).await
+    }
+
+    /// The endpoint and JSON body the request is sent as, in the provider's format.
+    fn request_body(&self, provider: &Provider, request: &ChatCompletionRequest) -> Result<(String, Value)> {
+        let url = format!("{}{}", self.config.openai_api_base_url, provider.api_format.path());
+        let body = match provider.api_format {
+            ApiFormat::ChatCompletions => serde_json::to_value(request)?,
+            ApiFormat::AnthropicMessages => anthropic::to_messages_request(&serde_json::to_value(request)?),
+        };
+        Ok((url, body))
+    }
+
+    /// Prints the request as it would be sent, with the API key redacted, in place of sending it.
+    fn print_dry_run(&self, request: &ChatCompletionRequest) -> Result<()> {
+        let provider = self.config.provider();
+        let (url, body) = self.request_body(provider, request)?;
+        
+        // Replace a thinking indicator rather than continuing its line
+        if io::stdout().is_terminal() {
+            print!("\r\x1b[2K");
+        }
+        println!("POST {}", url);
+        if let Some((name, value)) = provider.redacted_auth_header() {
+            println!("{}: {}", name, value);
+        }
+        for (name, value) in provider.default_headers {
+            println!("{}: {}", name, value);
+        }
+        println!("\n{}", serde_json::to_string_pretty(&body)?);
+        Ok(())
+    }
+
+    /// Posts a JSON body to `url` with the provider's auth, retrying as described for `post`.
+    async fn post_json(&self, provider: &Provider, url: &str, body: &Value, streaming: bool) -> Result<reqwest::Response> {
+        let timeout = self.config.request_timeout();
+        
+        let mut attempt = 0;
+        loop {
+            let mut req_builder = self.client.post(url)
+                .header("Content-Type", "application/json");
+                
+            for (name, value) in provider.default_headers {
+                req_builder = req_builder.header(*name, *value);
+            }
+            if let Some((name, value)) = provider.auth_header_value(self.config.openai_api_key.expose()) {
+                // Marked sensitive so it is elided should the request ever be debug-printed
+                let mut value = reqwest::header::HeaderValue::from_str(&value)
+                    .map_err(|_| anyhow!("The API key contains characters that can't be sent in a header"))?;
+                value.set_sensitive(true);
+                req_builder = req_builder.header(name, value);
+            }
+            match provider.redacted_auth_header() {
+                Some((name, value)) => debug!("POST {} ({}: {})", url, name, value),
+                None => debug!("POST {}", url),
+            }
+            // A stream may legitimately run for longer; its chunks are timed separately
+            if !streaming {
+                req_builder = req_builder.timeout(timeout);
+            }
+            
+            let sent = tokio::time::timeout(timeout, req_builder.json(body).send()).await;
+            let response = match sent {
+                Ok(Ok(response)) => response,
+                Ok(Err(e)) if !e.is_timeout() => return Err(e.into()),
+                _ => return Err(anyhow!("API did not respond within {} seconds", timeout.as_secs())),
             };
             
-            Ok(Message::assistant(content))
-        } else {
-            Err(anyhow!("No choices in API response"))
+            // Handle the response
+            let status = response.status();
+            if status.is_success() {
+                return Ok(response);
+            }
+            
+            let retryable = status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+            if retryable && attempt < self.config.max_retries {
+                attempt += 1;
+                let delay = retry_after(&response).unwrap_or_else(|| self.backoff_delay(attempt));
+                warn!("API returned {}, retrying in {:?} (attempt {}/{})", status, delay, attempt, self.config.max_retries);
+                *self.retries.lock().unwrap() += 1;
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            
+            let error = ApiError::from_response(status, &response.text().await?);
+            if status == reqwest::StatusCode::NOT_FOUND && !self.config.openai_api_base_url.ends_with("/v1") {
+                return Err(anyhow!(
+                    "{}\nHint: the base URL {} has no /v1 suffix; try {}/v1",
+                    error, self.config.openai_api_base_url, self.config.openai_api_base_url
+                ));
+            }
+            return Err(error.into());
+        }
+    }
+
+    /// The request as written to debug logs; without `log_prompts` only its shape is shown.
+    fn loggable_request(&self, request: &ChatCompletionRequest) -> String {
+        if self.config.log_prompts {
+            return format!("{:?}", request);
         }
+        format!(
+            "model {} with {} messages and {} tools (content hidden, LOG_PROMPTS=false)",
+            request.model,
+            request.messages.len(),
+            request.tools.as_ref().map_or(0, Vec::len)
+        )
+    }
+
+    /// Answers from the mock provider, retrying its simulated rate limits like real ones.
+    async fn mock_complete(&self, request: &ChatCompletionRequest) -> Result<Value> {
+        let mut attempt = 0;
+        loop {
+            match mock::complete(&request.model, &request.messages, self.config.mock_error_rate).await {
+                Err(e) if attempt < self.config.max_retries => {
+                    attempt += 1;
+                    let delay = self.backoff_delay(attempt);
+                    warn!("{}, retrying in {:?} (attempt {}/{})", e, delay, attempt, self.config.max_retries);
+                    *self.retries.lock().unwrap() += 1;
+                    tokio::time::sleep(delay).await;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// Delay before retry number `attempt` (counting from 1): the base backoff, doubled each time.
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        Duration::from_millis(self.config.retry_backoff_ms.saturating_mul(1 << (attempt - 1).min(16)))
+    }
+
+    /// Builds the assistant message from response content: runs the configured
+    /// post-processors and attaches timing statistics and metadata. A non-streamed
+    /// response arrives as a single chunk.
+    fn finish(&self, content: String, response_time: Duration, usage: Option<TokenUsage>) -> Message {
+        let content = postprocess::apply_all(&self.config.post_processors, content);
+        let mut message = Message::assistant(content);
+        message.stream_stats = Some(StreamStats::new(1, response_time, response_time, &message.content));
+        message.metadata = Some(ResponseMetadata {
+            retries: std::mem::take(&mut *self.retries.lock().unwrap()),
+            latency_ms: response_time.as_millis() as u64,
+            usage,
+            ..ResponseMetadata::default()
+        });
+        message
     }
 
+    /// Checks every tool definition against the shape the chat completions API
+    /// expects, so a malformed schema fails at startup instead of being ignored.
+    pub fn validate_tools(&self) -> Result<()> {
+        for (index, tool) in self.get_tools().iter().enumerate() {
+            let name = tool["function"]["name"].as_str().map(str::to_string).unwrap_or_else(|| format!("#{}", index + 1));
+            validate_tool_schema(tool).map_err(|e| anyhow!("Tool {} has an invalid schema: {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    /// Tool definitions sent to the model: the curated Context7 tools when that
+    /// server is configured, followed by the tools listed by every other server.
     fn get_tools(&self) -> Vec<Value> {
-        vec![
-            json!({
-                "type": "function",
-                "function": {
-                    "name": "mcp_context7_resolve_library_id",
-                    "description": "Required first step: Resolves a general package name into a Context7-compatible library ID. Must be called before using 'get-library-docs' to retrieve a valid Context7-compatible library ID.",
-                    "parameters": {
-                        "type": "object",
-                        "properties": {
-                            "libraryName": {
-                                "type": "string",
-                                "description": "Library name to search for and retrieve a Context7-compatible library ID."
-                            }
-                        },
-                        "required": ["libraryName"]
-                    }
+        let mut tools = if self.config.mcp_servers.servers.contains_key(mcp::CONTEXT7) {
+            context7_tools()
+        } else {
+            Vec::new()
+        };
+        tools.extend(mcp::mcp_tools().iter().filter(|tool| tool.server != mcp::CONTEXT7).map(|tool| json!({
+            "type": "function",
+            "function": {
+                "name": mcp_function_name(tool),
+                "description": tool.description,
+                "parameters": tool.input_schema,
+            }
+        })));
+        tools
+    }
+}
+
+/// The function name a server's tool is offered under, e.g. `mcp_git_git_status`.
+/// Characters function names may not contain become underscores.
+fn mcp_function_name(tool: &mcp::McpTool) -> String {
+    format!("mcp_{}_{}", tool.server, tool.name)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+fn context7_tools() -> Vec<Value> {
+    vec![
+        json!({
+            "type": "function",
+            "function": {
+                "name": "mcp_context7_resolve_library_id",
+                "description": "Required first step: Resolves a general package name into a Context7-compatible library ID. Must be called before using 'get-library-docs' to retrieve a valid Context7-compatible library ID.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "libraryName": {
+                            "type": "string",
+                            "description": "Library name to search for and retrieve a Context7-compatible library ID."
+                        }
+                    },
+                    "required": ["libraryName"]
                 }
-            }),
-            json!({
-                "type": "function",
-                "function": {
-                    "name": "mcp_context7_get_library_docs",
-                    "description": "Fetches up-to-date documentation for a library. You must call 'resolve-library-id' first to obtain the exact Context7-compatible library ID required to use this tool.",
-                    "parameters": {
-                        "type": "object",
-                        "properties": {
-                            "context7CompatibleLibraryID": {
-                                "type": "string",
-                                "description": "Exact Context7-compatible library ID (e.g., 'mongodb/docs', 'vercel/nextjs') retrieved from 'resolve-library-id'."
-                            },
-                            "tokens": {
-                                "type": "number",
-                                "description": "Maximum number of tokens of documentation to retrieve (default: 5000). Higher values provide more context but consume more tokens."
-                            },
-                            "topic": {
-                                "type": "string",
-                                "description": "Topic to focus documentation on (e.g., 'hooks', 'routing')."
-                            }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "mcp_context7_get_library_docs",
+                "description": "Fetches up-to-date documentation for a library. You must call 'resolve-library-id' first to obtain the exact Context7-compatible library ID required to use this tool.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "context7CompatibleLibraryID": {
+                            "type": "string",
+                            "description": "Exact Context7-compatible library ID (e.g., 'mongodb/docs', 'vercel/nextjs') retrieved from 'resolve-library-id'."
+                        },
+                        "tokens": {
+                            "type": "number",
+                            "description": "Maximum number of tokens of documentation to retrieve (default: 5000). Higher values provide more context but consume more tokens."
                         },
-                        "required": ["context7CompatibleLibraryID"]
-                    }
+                        "topic": {
+                            "type": "string",
+                            "description": "Topic to focus documentation on (e.g., 'hooks', 'routing')."
+                        }
+                    },
+                    "required": ["context7CompatibleLibraryID"]
                 }
-            })
-        ]
+            }
+        })
+    ]
+}
+
+/// The delay a `Retry-After` header asks for, when given in seconds.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let seconds = response.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Validates one tool against the OpenAI tool shape: `type: function`, a named
+/// `function`, and object `parameters` whose `required` entries are all declared.
+fn validate_tool_schema(tool: &Value) -> Result<()> {
+    if tool["type"] != "function" {
+        return Err(anyhow!("\"type\" must be \"function\""));
+    }
+    if tool["function"]["name"].as_str().is_none_or(str::is_empty) {
+        return Err(anyhow!("\"function.name\" must be a non-empty string"));
+    }
+    
+    let parameters = &tool["function"]["parameters"];
+    if parameters["type"] != "object" {
+        return Err(anyhow!("\"function.parameters.type\" must be \"object\""));
+    }
+    let Some(properties) = parameters["properties"].as_object() else {
+        return Err(anyhow!("\"function.parameters.properties\" must be an object"));
+    };
+    if let Some(required) = parameters.get("required") {
+        let Some(required) = required.as_array() else {
+            return Err(anyhow!("\"function.parameters.required\" must be an array"));
+        };
+        for name in required {
+            match name.as_str() {
+                Some(name) if properties.contains_key(name) => {},
+                _ => return Err(anyhow!("required parameter {} is not declared in properties", name)),
+            }
+        }
     }
-} 
\ No newline at end of file
+    
+    Ok(())
+}
+
+/// Fills in the placeholders of a context injection template.
+fn render_context_note(template: &str) -> String {
+    let now = Local::now();
+    let cwd = std::env::current_dir()
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_default();
+    
+    template
+        .replace("{date}", &now.format("%A, %B %-d, %Y").to_string())
+        .replace("{time}", &now.format("%H:%M %Z").to_string())
+        .replace("{cwd}", &cwd)
+        .replace("{os}", std::env::consts::OS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(delta: Value) -> String {
+        format!("data: {}\n\n", json!({ "id": "s", "choices": [{ "index": 0, "delta": delta }] }))
+    }
+
+    #[test]
+    fn assembles_tool_call_fragments_across_chunks() {
+        let mut reader = StreamReader::new(ApiFormat::ChatCompletions, None);
+        let fragments = [
+            json!({ "tool_calls": [{ "index": 0, "id": "call_1", "type": "function", "function": { "name": "mcp_context7_", "arguments": "" } }] }),
+            json!({ "tool_calls": [{ "index": 0, "function": { "name": "resolve_library_id", "arguments": "{\"library" } }] }),
+            json!({ "tool_calls": [{ "index": 0, "function": { "arguments": "Name\": \"re" } }] }),
+            json!({ "tool_calls": [{ "index": 0, "function": { "arguments": "act\"}" } }] }),
+        ];
+        for fragment in fragments {
+            reader.feed(chunk(fragment).as_bytes()).unwrap();
+        }
+        
+        let calls = reader.tool_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].function.name, "mcp_context7_resolve_library_id");
+        let arguments: Value = serde_json::from_str(&calls[0].function.arguments).unwrap();
+        assert_eq!(arguments, json!({ "libraryName": "react" }));
+    }
+}
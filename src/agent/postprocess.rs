@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+/// Leading filler phrases removed by [`PostProcessor::StripFiller`].
+const FILLER_PREFIXES: &[&str] = &[
+    "sure!",
+    "sure,",
+    "certainly!",
+    "certainly,",
+    "of course!",
+    "of course,",
+    "absolutely!",
+    "great question!",
+];
+
+/// A pure transform applied to assistant content before it is displayed and saved.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcessor {
+    /// Removes leading and trailing whitespace
+    Trim,
+    /// Removes a leading "Sure! "-style filler phrase
+    StripFiller,
+    /// Converts tabs to four spaces, strips trailing spaces and collapses runs of blank lines
+    NormalizeWhitespace,
+}
+
+impl PostProcessor {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "trim" => Some(Self::Trim),
+            "strip_filler" => Some(Self::StripFiller),
+            "normalize_whitespace" => Some(Self::NormalizeWhitespace),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, content: &str) -> String {
+        match self {
+            Self::Trim => content.trim().to_string(),
+            Self::StripFiller => strip_filler(content),
+            Self::NormalizeWhitespace => normalize_whitespace(content),
+        }
+    }
+}
+
+/// Runs the processors over `content` in order.
+pub fn apply_all(processors: &[PostProcessor], content: String) -> String {
+    processors.iter().fold(content, |content, processor| processor.apply(&content))
+}
+
+fn strip_filler(content: &str) -> String {
+    let start = content.trim_start();
+    let lower = start.to_lowercase();
+    
+    match FILLER_PREFIXES.iter().find(|prefix| lower.starts_with(*prefix)) {
+        // Filler prefixes are ASCII, so the byte length matches in the original
+        Some(prefix) => start[prefix.len()..].trim_start().to_string(),
+        None => content.to_string(),
+    }
+}
+
+fn normalize_whitespace(content: &str) -> String {
+    let mut lines = Vec::new();
+    let mut blank_run = 0;
+    
+    for line in content.lines() {
+        let line = line.replace('\t', "    ");
+        let line = line.trim_end();
+        
+        if line.is_empty() {
+            blank_run += 1;
+            if blank_run > 1 {
+                continue;
+            }
+        } else {
+            blank_run = 0;
+        }
+        lines.push(line.to_string());
+    }
+    
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_removes_surrounding_whitespace() {
+        assert_eq!(PostProcessor::Trim.apply("\n  answer\t\n\n"), "answer");
+    }
+
+    #[test]
+    fn strip_filler_removes_a_leading_phrase_in_any_case() {
+        assert_eq!(PostProcessor::StripFiller.apply("  SURE! Here it is."), "Here it is.");
+        assert_eq!(PostProcessor::StripFiller.apply("Of course, naïve café."), "naïve café.");
+        assert_eq!(PostProcessor::StripFiller.apply("Surely not."), "Surely not.");
+    }
+
+    #[test]
+    fn normalize_whitespace_expands_tabs_and_collapses_blank_lines() {
+        assert_eq!(
+            PostProcessor::NormalizeWhitespace.apply("a  \n\tb\n\n\n\nc \n"),
+            "a\n    b\n\nc"
+        );
+    }
+
+    #[test]
+    fn apply_all_runs_processors_in_the_configured_order() {
+        let content = "  Sure!\tHere:\n\n\n\tdone  \n";
+        let processors = [PostProcessor::StripFiller, PostProcessor::NormalizeWhitespace, PostProcessor::Trim];
+        
+        let by_hand = PostProcessor::Trim.apply(&PostProcessor::NormalizeWhitespace.apply(&PostProcessor::StripFiller.apply(content)));
+        assert_eq!(apply_all(&processors, content.to_string()), by_hand);
+        assert_eq!(by_hand, "Here:\n\n    done");
+        assert_eq!(apply_all(&[], content.to_string()), content);
+    }
+}
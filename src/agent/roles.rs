@@ -0,0 +1,52 @@
+use anyhow::{Result, anyhow};
+use serde_json::{json, Value};
+
+/// Stands in for a missing user turn when a conversation would otherwise start with the assistant.
+const PLACEHOLDER_USER_CONTENT: &str = "(continuing the conversation)";
+
+/// Rewrites chat completions messages into the strict order some backends demand:
+/// system messages first, then alternating turns starting with `user`. Consecutive
+/// user or assistant messages are merged, and a placeholder user turn is inserted
+/// before a leading assistant message.
+pub fn normalize_roles(messages: Vec<Value>) -> Result<Vec<Value>> {
+    let mut normalized: Vec<Value> = Vec::with_capacity(messages.len());
+    
+    for message in messages {
+        let role = message["role"].as_str().unwrap_or_default().to_string();
+        
+        if role == "system" {
+            if normalized.iter().any(|m| m["role"] != "system") {
+                return Err(anyhow!("Cannot normalize message roles: a system message follows the conversation turns"));
+            }
+            normalized.push(message);
+            continue;
+        }
+        
+        let previous_role = normalized.last().map(|m| m["role"].as_str().unwrap_or_default().to_string());
+        match (previous_role.as_deref(), role.as_str()) {
+            (None | Some("system"), "assistant") => {
+                normalized.push(json!({ "role": "user", "content": PLACEHOLDER_USER_CONTENT }));
+                normalized.push(message);
+            },
+            (Some(previous), current) if previous == current && matches!(current, "user" | "assistant") => {
+                let (Some(earlier), Some(content)) = (
+                    normalized.last().and_then(|m| m["content"].as_str()),
+                    message["content"].as_str(),
+                ) else {
+                    return Err(anyhow!("Cannot normalize message roles: consecutive {} messages have non-text content", current));
+                };
+                let merged = format!("{}\n\n{}", earlier, content);
+                if let Some(last) = normalized.last_mut() {
+                    last["content"] = Value::String(merged);
+                }
+            },
+            _ => normalized.push(message),
+        }
+    }
+    
+    if !normalized.iter().any(|m| m["role"] == "user") {
+        return Err(anyhow!("Cannot normalize message roles: the conversation has no user message"));
+    }
+    
+    Ok(normalized)
+}
@@ -0,0 +1,64 @@
+/// Splits a `text/event-stream` body into the payloads of its `data:` lines.
+///
+/// Network reads can end anywhere, even inside a multi-byte character, so bytes
+/// are buffered until a full line has arrived and only then decoded.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+}
+
+/// One event payload from the stream.
+#[derive(Debug, PartialEq)]
+pub enum SseEvent {
+    Data(String),
+    /// The `data: [DONE]` sentinel that ends an OpenAI stream
+    Done,
+}
+
+impl SseParser {
+    /// Adds a chunk read from the network and returns the events completed by it.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            // Comments (": keep-alive"), event names and ids carry nothing we use
+            let Some(data) = line.strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                events.push(SseEvent::Done);
+            } else if !data.is_empty() {
+                events.push(SseEvent::Data(data.to_string()));
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reassembles_a_character_split_across_reads() {
+        let line = "data: {\"content\":\"🚀\"}\n".as_bytes();
+        let split = line.iter().position(|&b| b == 0xF0).unwrap() + 2;
+        
+        let mut parser = SseParser::default();
+        assert!(parser.feed(&line[..split]).is_empty());
+        assert_eq!(parser.feed(&line[split..]), vec![SseEvent::Data("{\"content\":\"🚀\"}".to_string())]);
+    }
+
+    #[test]
+    fn skips_comments_and_recognizes_done() {
+        let mut parser = SseParser::default();
+        let events = parser.feed(b": keep-alive\r\n\r\nevent: message\ndata: 1\n\ndata: [DONE]\n");
+        assert_eq!(events, vec![SseEvent::Data("1".to_string()), SseEvent::Done]);
+    }
+}
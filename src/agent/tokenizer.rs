@@ -0,0 +1,41 @@
+//! Token counting with the model's own BPE encoding, for budgeting context.
+
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::CoreBPE;
+
+use crate::util::estimate_tokens;
+use super::conversation::Conversation;
+
+/// Tokens added per message for its role and formatting.
+const MESSAGE_OVERHEAD: usize = 4;
+
+/// Counts the tokens `text` takes up for `model`. Models tiktoken doesn't know,
+/// such as those served by Ollama or Groq, fall back to the four-characters-per-token estimate.
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    match encoding(model) {
+        Some(bpe) => bpe.encode_with_special_tokens(text).len(),
+        None => estimate_tokens(text),
+    }
+}
+
+/// Counts the tokens of every message in `conversation`, including per-message overhead.
+pub fn count_conversation_tokens(model: &str, conversation: &Conversation) -> usize {
+    conversation
+        .messages
+        .iter()
+        .map(|message| count_tokens(model, &message.content) + MESSAGE_OVERHEAD)
+        .sum()
+}
+
+/// The encoding for `model`, ignoring a routing prefix such as `openai/`.
+/// Encodings are loaded once and shared.
+fn encoding(model: &str) -> Option<&'static CoreBPE> {
+    let model = model.rsplit('/').next().unwrap_or(model);
+    match get_tokenizer(model)? {
+        Tokenizer::O200kBase => Some(tiktoken_rs::o200k_base_singleton()),
+        Tokenizer::Cl100kBase => Some(tiktoken_rs::cl100k_base_singleton()),
+        Tokenizer::P50kBase => Some(tiktoken_rs::p50k_base_singleton()),
+        Tokenizer::P50kEdit => Some(tiktoken_rs::p50k_edit_singleton()),
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => Some(tiktoken_rs::r50k_base_singleton()),
+    }
+}
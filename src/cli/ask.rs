@@ -0,0 +1,147 @@
+use anyhow::Result;
+use futures::StreamExt;
+use std::io::{self, IsTerminal, Read, Write};
+use std::pin::pin;
+use std::time::Instant;
+
+use crate::agent::{Conversation, ConversationList, Message, OpenAIAgent};
+use crate::config::Config;
+use crate::mcp;
+use crate::util::sanitize_terminal_output;
+use tracing::warn;
+use super::repl::{add_response, docs_tools_available, fresh_conversation, save_conversation};
+
+/// Answers a single prompt without entering the REPL, printing only the response.
+/// With `resume_last`, the prompt continues the most recently active conversation.
+/// The answer is streamed when stdout is a terminal, unless `no_stream` is set or
+/// streaming is turned off; pipes always get it in one piece.
+pub async fn ask(mut config: Config, prompt: Option<String>, resume_last: bool, no_stream: bool) -> Result<()> {
+    let prompt = read_prompt(prompt)?;
+    
+    // Start the MCP servers up front so the system prompt can reflect whether they came up
+    if config.mcp_enabled && config.provider().supports_tools {
+        if let Err(e) = mcp::ensure_mcp_servers_running(&config).await {
+            warn!("Some MCP servers are unavailable: {}", e);
+        }
+    }
+    if !docs_tools_available(&config) {
+        config.use_prompt_without_docs_tools();
+    }
+    
+    let list_path = config.history_path.join("conversations.json");
+    let mut conversation_list = ConversationList::load_from_file(&list_path).unwrap_or_else(|_| ConversationList::new());
+    
+    let mut conversation = if resume_last {
+        resume_last_conversation(&config)?
+    } else {
+        fresh_conversation(&config)
+    };
+    conversation.add_message(Message::user(prompt));
+    
+    let agent = OpenAIAgent::new(config.clone());
+    agent.validate_tools()?;
+    if config.summarize_after_tokens > 0
+        && !config.dry_run
+        && conversation.total_tokens_estimate(&config.openai_api_model) > config.summarize_after_tokens
+    {
+        if let Err(e) = agent.summarize_history(&mut conversation, config.summary_keep_recent.max(1)).await {
+            eprintln!("Could not summarize earlier messages: {}", e);
+        }
+    }
+    let result = if config.stream && !no_stream && io::stdout().is_terminal() {
+        stream_answer(&agent, &conversation).await
+    } else {
+        agent.chat(&conversation).await.inspect(|response| print_answer(&response.content))
+    };
+    let _ = mcp::stop_mcp_servers().await;
+    let response = result?;
+    
+    // A dry run sent nothing, so there is no answer worth keeping
+    if config.dry_run {
+        return Ok(());
+    }
+    add_response(&mut conversation, &agent, response);
+    
+    save_conversation(&agent, &mut conversation, &mut conversation_list, &config).await?;
+    config.remember_last_conversation(&conversation.id);
+    
+    Ok(())
+}
+
+/// Prints the answer as it arrives and assembles it into a message.
+async fn stream_answer(agent: &OpenAIAgent, conversation: &Conversation) -> Result<Message> {
+    let started = Instant::now();
+    let mut stream = pin!(agent.chat_stream(conversation));
+    let mut content = String::new();
+    let mut chunks = 0;
+    let mut first_token = None;
+    
+    while let Some(piece) = stream.next().await {
+        let piece = match piece {
+            Ok(piece) => piece,
+            Err(e) => {
+                // The partial answer is dropped; just end its line so the error starts on a fresh one
+                if !content.is_empty() {
+                    println!();
+                }
+                return Err(e);
+            },
+        };
+        first_token.get_or_insert_with(|| started.elapsed());
+        // Model output is untrusted: never let it drive the terminal
+        print!("{}", sanitize_terminal_output(&piece));
+        io::stdout().flush()?;
+        content.push_str(&piece);
+        chunks += 1;
+    }
+    println!();
+    
+    let total = started.elapsed();
+    Ok(agent.streamed_message(content, chunks, first_token.unwrap_or(total), total))
+}
+
+/// Prints a complete answer. Pipes get it verbatim, but on a terminal the untrusted
+/// model output is sanitized like a streamed answer.
+fn print_answer(content: &str) {
+    if io::stdout().is_terminal() {
+        println!("{}", sanitize_terminal_output(content));
+    } else {
+        println!("{}", content);
+    }
+}
+
+/// Combines the prompt argument with piped stdin, so `ask "summarize this" < file.txt` works.
+fn read_prompt(prompt: Option<String>) -> Result<String> {
+    let mut input = String::new();
+    if !io::stdin().is_terminal() {
+        io::stdin().read_to_string(&mut input)?;
+    }
+    let input = input.trim();
+    
+    let prompt = match prompt {
+        Some(prompt) if input.is_empty() => prompt,
+        Some(prompt) => format!("{}\n\n{}", prompt, input),
+        None => input.to_string(),
+    };
+    if prompt.trim().is_empty() {
+        anyhow::bail!("No prompt given; pass one as an argument or pipe it on stdin");
+    }
+    Ok(prompt)
+}
+
+fn resume_last_conversation(config: &Config) -> Result<Conversation> {
+    let Some(id) = config.last_conversation_id() else {
+        anyhow::bail!("No previous conversation to resume");
+    };
+    
+    let path = config.history_path.join(format!("{}.json", id));
+    if !path.exists() {
+        // The REPL records conversations as soon as they start, before anything is saved
+        eprintln!("Last conversation {} was never saved; starting a new one", id);
+        let mut conversation = fresh_conversation(config);
+        conversation.id = id;
+        return Ok(conversation);
+    }
+    
+    Conversation::load_from_file(&path)
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+use colored::*;
+use fs_err as fs;
+
+use crate::agent::{Conversation, ConversationList};
+use crate::config::Config;
+
+/// Rewrites every saved conversation in the current on-disk format and rebuilds
+/// `conversations.json` from the conversation files themselves.
+pub fn compact_history(config: &Config) -> Result<()> {
+    let list_path = config.history_path.join("conversations.json");
+    let mut conversation_list = ConversationList::new();
+    let mut total = 0;
+    let mut migrated = 0;
+    let mut failed = 0;
+    
+    if config.history_path.exists() {
+        for entry in fs::read_dir(&config.history_path)? {
+            let path = entry?.path();
+            
+            // Only conversation files, not the index, embedding caches or leftover temporary files
+            let is_embeddings = path.to_string_lossy().ends_with(".embeddings.json");
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") || path == list_path || is_embeddings {
+                continue;
+            }
+            
+            match Conversation::compact_file(&path) {
+                Ok((conversation, changed)) => {
+                    total += 1;
+                    if changed {
+                        migrated += 1;
+                    }
+                    conversation_list.add_conversation(&conversation);
+                },
+                Err(e) => {
+                    failed += 1;
+                    println!("{} Skipping {}: {}", "Warning:".yellow().bold(), path.display(), e);
+                }
+            }
+        }
+    }
+    
+    conversation_list.save_to_file(&list_path)?;
+    
+    println!(
+        "Compacted {} conversation{} ({} migrated, {} failed) in {}",
+        total,
+        if total == 1 { "" } else { "s" },
+        migrated,
+        failed,
+        config.history_path.display()
+    );
+    
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use anyhow::Result;
+use fs_err as fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use crate::agent::{Conversation, ConversationList, Role};
+use crate::config::Config;
+use crate::util::{extract_code_blocks, CodeBlock};
+
+/// Archives every saved conversation as a zip of Markdown files plus the
+/// `conversations.json` index, with system instructions only if `include_system` is set.
+/// Returns the archive size in bytes.
+pub fn export_all(config: &Config, conversation_list: &ConversationList, path: &Path, include_system: bool) -> Result<u64> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    
+    let mut zip = ZipWriter::new(fs::File::create(path)?);
+    let options = FileOptions::default();
+    
+    for summary in &conversation_list.conversations {
+        let conv_path = config.history_path.join(format!("{}.json", summary.id));
+        let conversation = Conversation::load_from_file(&conv_path)?;
+        
+        zip.start_file(format!("conversations/{}.md", summary.id), options)?;
+        zip.write_all(conversation.to_markdown(include_system).as_bytes())?;
+    }
+    
+    zip.start_file("conversations.json", options)?;
+    zip.write_all(serde_json::to_string_pretty(conversation_list)?.as_bytes())?;
+    zip.finish()?;
+    
+    Ok(fs::metadata(path)?.len())
+}
+
+/// Writes `conversation` to `path` as a Markdown document, system messages included.
+pub fn export_markdown(conversation: &Conversation, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, conversation.to_markdown(true))?;
+    Ok(())
+}
+
+/// Writes every fenced code block from the assistant messages of `conversation` into
+/// `dir` as `snippet-<n>.<ext>`, numbered past any files already there. Returns the
+/// paths written.
+pub fn export_code(conversation: &Conversation, dir: &Path) -> Result<Vec<PathBuf>> {
+    let blocks: Vec<CodeBlock> = conversation.messages
+        .iter()
+        .filter(|m| matches!(m.role, Role::Assistant))
+        .flat_map(|m| extract_code_blocks(&m.content))
+        .collect();
+    if blocks.is_empty() {
+        return Ok(Vec::new());
+    }
+    
+    fs::create_dir_all(dir)?;
+    let mut written = Vec::new();
+    let mut number = 1;
+    
+    for block in blocks {
+        let extension = extension_for(&block.language);
+        let path = loop {
+            let candidate = dir.join(format!("snippet-{}.{}", number, extension));
+            number += 1;
+            if !candidate.exists() {
+                break candidate;
+            }
+        };
+        fs::write(&path, block.code)?;
+        written.push(path);
+    }
+    
+    Ok(written)
+}
+
+/// File extension for a code block's language tag; unknown or missing tags get `txt`.
+fn extension_for(language: &str) -> String {
+    let language = language.to_lowercase();
+    let extension = match language.as_str() {
+        "python" | "py" => "py",
+        "rust" | "rs" => "rs",
+        "javascript" | "js" | "jsx" => "js",
+        "typescript" | "ts" | "tsx" => "ts",
+        "bash" | "sh" | "shell" | "zsh" | "console" => "sh",
+        "yaml" | "yml" => "yml",
+        "markdown" | "md" => "md",
+        "c++" | "cpp" | "cxx" => "cpp",
+        "csharp" | "c#" | "cs" => "cs",
+        "kotlin" | "kt" => "kt",
+        "ruby" | "rb" => "rb",
+        "golang" | "go" => "go",
+        "json" | "toml" | "html" | "css" | "sql" | "java" | "c" | "h" | "xml" | "php" | "swift" => &language,
+        _ => "txt",
+    };
+    extension.to_string()
+}
+
+/// Default archive name in the current directory, stamped with today's date.
+pub fn default_archive_path() -> PathBuf {
+    PathBuf::from(format!("ai-agent-history-{}.zip", chrono::Local::now().format("%Y-%m-%d")))
+}
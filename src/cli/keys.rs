@@ -0,0 +1,29 @@
+use anyhow::{bail, Result};
+use colored::*;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use crate::config::Config;
+
+/// Reads an API key from stdin and stores it in the OS keyring for the configured provider.
+pub fn set_key(config: &Config) -> Result<()> {
+    let provider = config.provider();
+    if io::stdin().is_terminal() {
+        print!("API key for {}: ", provider.name);
+        io::stdout().flush()?;
+    }
+    
+    let mut key = String::new();
+    io::stdin().lock().read_line(&mut key)?;
+    let key = key.trim();
+    if key.is_empty() {
+        bail!("No API key given");
+    }
+    
+    config.store_api_key(key)?;
+    println!(
+        "{} Stored the {} API key in the OS keyring; it is used instead of OPENAI_API_KEY from now on.",
+        "Done.".green().bold(),
+        provider.name
+    );
+    Ok(())
+}
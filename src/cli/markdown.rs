@@ -0,0 +1,129 @@
+use colored::*;
+use once_cell::sync::Lazy;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::as_24_bit_terminal_escaped;
+
+static SYNTAXES: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+static THEME: Lazy<Theme> = Lazy::new(|| {
+    let mut themes = ThemeSet::load_defaults();
+    themes.themes.remove("base16-ocean.dark").unwrap_or_default()
+});
+
+/// Renders Markdown for the terminal one line at a time, so streamed responses can be
+/// shown as each line completes. Fenced code blocks are syntax highlighted; everything
+/// else gets styled headings, bullets, quotes and inline emphasis.
+///
+/// Input must already be sanitized: the renderer adds its own escape sequences.
+#[derive(Default)]
+pub struct MarkdownRenderer {
+    code: Option<HighlightLines<'static>>,
+}
+
+impl MarkdownRenderer {
+    /// Renders a whole response.
+    pub fn render(text: &str) -> String {
+        let mut renderer = Self::default();
+        text.lines().map(|line| renderer.render_line(line)).collect::<Vec<_>>().join("\n")
+    }
+
+    /// Renders a single line (without its newline), tracking whether it is inside a code block.
+    pub fn render_line(&mut self, line: &str) -> String {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            self.code = match self.code {
+                Some(_) => None,
+                None => {
+                    let language = trimmed[3..].trim();
+                    let syntax = SYNTAXES
+                        .find_syntax_by_token(language)
+                        .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+                    Some(HighlightLines::new(syntax, &THEME))
+                },
+            };
+            return line.dimmed().to_string();
+        }
+        
+        if let Some(highlighter) = &mut self.code {
+            if !colored::control::SHOULD_COLORIZE.should_colorize() {
+                return line.to_string();
+            }
+            return match highlighter.highlight_line(&format!("{}\n", line), &SYNTAXES) {
+                Ok(ranges) => format!("{}\x1b[0m", as_24_bit_terminal_escaped(&ranges, false).trim_end_matches('\n')),
+                Err(_) => line.to_string(),
+            };
+        }
+        
+        let indent = &line[..line.len() - trimmed.len()];
+        let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+        if (1..=6).contains(&hashes) && trimmed[hashes..].starts_with(' ') {
+            let heading = trimmed[hashes..].trim();
+            return if hashes == 1 {
+                heading.bold().underline().to_string()
+            } else {
+                heading.bold().cyan().to_string()
+            };
+        }
+        let marks: Vec<char> = trimmed.chars().filter(|c| !c.is_whitespace()).collect();
+        if marks.len() >= 3 && matches!(marks[0], '-' | '*' | '_') && marks.iter().all(|&c| c == marks[0]) {
+            return "─".repeat(40).dimmed().to_string();
+        }
+        if let Some(item) = ["- ", "* ", "+ "].iter().find_map(|bullet| trimmed.strip_prefix(bullet)) {
+            return format!("{}{} {}", indent, "•".cyan(), render_inline(item));
+        }
+        if let Some(quote) = trimmed.strip_prefix('>') {
+            return format!("{}{} {}", indent, "│".dimmed(), render_inline(quote.trim_start()));
+        }
+        render_inline(line)
+    }
+}
+
+/// Styles inline code, bold, italic and links within a line.
+fn render_inline(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    
+    while let Some(start) = rest.find(['`', '*', '[']) {
+        output.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        
+        let styled = if let Some(code) = tail.strip_prefix('`') {
+            code.find('`').map(|end| (code[..end].yellow().to_string(), end + 2))
+        } else if let Some(bold) = tail.strip_prefix("**") {
+            bold.find("**")
+                .filter(|&end| end > 0)
+                .map(|end| (bold[..end].bold().to_string(), end + 4))
+        } else if let Some(italic) = tail.strip_prefix('*') {
+            italic
+                .find('*')
+                .filter(|&end| end > 0 && !italic.starts_with(' '))
+                .map(|end| (italic[..end].italic().to_string(), end + 2))
+        } else {
+            // [text](url)
+            tail[1..].find("](").and_then(|close| {
+                let label = &tail[1..close + 1];
+                let after = &tail[close + 3..];
+                after.find(')').map(|end| {
+                    let url = &after[..end];
+                    (format!("{} ({})", label.underline(), url.dimmed()), close + 3 + end + 1)
+                })
+            })
+        };
+        
+        match styled {
+            Some((styled, consumed)) => {
+                output.push_str(&styled);
+                rest = &tail[consumed..];
+            },
+            None => {
+                // Not markup after all: keep the marker character as text
+                let marker = tail.chars().next().map_or(1, char::len_utf8);
+                output.push_str(&tail[..marker]);
+                rest = &tail[marker..];
+            },
+        }
+    }
+    output.push_str(rest);
+    output
+}
@@ -1,3 +1,12 @@
+mod ask;
+mod compact;
+mod export;
+mod keys;
+mod markdown;
 mod repl;
+mod settings;
 
+pub use ask::ask;
+pub use compact::compact_history;
+pub use keys::set_key;
 pub use repl::start_chat; 
\ No newline at end of file
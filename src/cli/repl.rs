@@ -1,13 +1,32 @@
 use anyhow::Result;
+use base64::prelude::*;
 use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
+use rustyline::{
+    Cmd, ConditionalEventHandler, DefaultEditor, Event, EventContext, EventHandler, KeyEvent, Movement, RepeatCount,
+};
+use serde_json::Value;
 use tracing::{error};
+use chrono::{DateTime, Local, Utc};
 use colored::*;
-use std::io::{self, Write};
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::pin::pin;
+use std::process::Command;
+use std::time::Instant;
+use fs_err as fs;
+use futures::StreamExt;
+use itertools::Itertools;
+use uuid::Uuid;
 
-use crate::agent::{Conversation, ConversationList, Message, OpenAIAgent, Role};
-use crate::config::Config;
+use crate::agent::{count_tokens, embeddings_path, semantic_search, ContentPart, Conversation, ConversationList, ConversationSummary, Message, OpenAIAgent, Role, StreamStats, TokenUsage};
+use crate::config::{self, Config, ReferenceMode, TitleStrategy};
 use crate::mcp;
+use crate::util::{elide_middle, sanitize_terminal_output, truncate_chars, wrap_text};
+use super::markdown::MarkdownRenderer;
+use super::{export, settings};
 
 const WELCOME_MESSAGE: &str = r#"
 ╭───────────────────────────────────────────╮
@@ -16,66 +35,190 @@ const WELCOME_MESSAGE: &str = r#"
 │                                           │
 ╰───────────────────────────────────────────╯
 
-Type your questions. Use these commands:
-  !help   - Show this help message
-  !exit   - Exit the chat
-  !new    - Start a new conversation
-  !list   - List saved conversations
-  !load   - Load a conversation by ID
-  !clear  - Clear the current conversation
-
-"#;
+Type your questions."#;
 
 const HELP_MESSAGE: &str = r#"Available commands:
-  !help   - Show this help message
-  !exit   - Exit the chat
-  !new    - Start a new conversation
-  !list   - List saved conversations
-  !load   - Load a conversation by ID
-  !clear  - Clear the current conversation
+  !help              - Show this help message
+  !exit              - Exit the chat
+  !new               - Start a new conversation
+  !list              - List saved conversations
+  !load [query]      - Load a conversation by ID, list number or title
+  !delete <id>       - Delete a saved conversation by ID or list number
+  !rename [title]    - Rename the current conversation
+  !clear             - Reset the current conversation (discards its messages)
+  !cls               - Clear the terminal screen, keeping the conversation
+  !edit-prompt       - Compose the next message in $EDITOR
+  !config            - View and change settings
+  !export [id] <md>  - Save a conversation (default: this one) as Markdown
+  !export-all [path] - Archive all conversations as Markdown in a zip file (--include-system)
+  !export-code <dir> - Save the code blocks from responses as files
+  !edit              - Edit the last question and send it again
+  !fork [index]      - Continue in a copy of this conversation up to a message
+  !regenerate        - Ask again for a different answer to the last question
+  !undo              - Remove the last question and its answer
+  !setsystem <text>  - Replace the base system prompt of this conversation
+  !addsystem <text>  - Layer additional system instructions onto this conversation
+  !resolve <library> - Look up a library's Context7 ID directly
+  !docs <id> [topic] - Fetch Context7 documentation directly
+  !last              - Show the last response in full
+  !params [k=v ...]  - Pin temperature, top_p or max_tokens to this conversation
+  !retry-last        - Send the last request again exactly as it was
+  !tokens            - Show token usage and estimated cost
+  !raw               - Show the last raw API response as JSON
+  !image <path|url>  - Attach an image to the next message
+  !timestamps on|off - Show when each message was written
+  !tee <file>|off    - Also append each response to a file
+  !find <query>      - Search all conversations for messages similar in meaning
+  !reference <query> - Add another conversation as context (append full or summary)
+  !compare <a> <b>   - Ask two models the next question and keep one answer
+  !whoami            - Show the effective configuration
+  !reload            - Try again to start MCP servers that failed to start
+  !replay <model>    - Re-ask this conversation's questions against another model
+
+Press Alt-S to set aside the line you are typing; it comes back after the next command.
 "#;
 
-pub async fn start_chat() -> Result<()> {
-    let config = Config::load()?;
+/// Matches shown by `!find`.
+const FIND_RESULTS: usize = 5;
+
+/// Key handler that moves the line being typed into a stash and clears the prompt.
+struct StashLine(Arc<Mutex<Option<String>>>);
+
+impl ConditionalEventHandler for StashLine {
+    fn handle(&self, _evt: &Event, _n: RepeatCount, _positive: bool, ctx: &EventContext) -> Option<Cmd> {
+        if ctx.line().is_empty() {
+            return Some(Cmd::Noop);
+        }
+        *self.0.lock().unwrap() = Some(ctx.line().to_string());
+        Some(Cmd::Kill(Movement::WholeBuffer))
+    }
+}
+
+/// Runs the interactive chat. With `append_file`, the first conversation is seeded
+/// from that transcript before the prompt appears.
+pub async fn start_chat(mut config: Config, append_file: Option<&Path>) -> Result<()> {
+    // Make sure conversations can actually be saved before the session starts
+    if let Err(e) = probe_writable(&config.history_path) {
+        let fallback = env::temp_dir().join("ai-agent-history");
+        println!(
+            "{} History directory {} is not writable ({}).\nConversations will be saved to {} instead and may not survive a reboot.",
+            "Warning:".yellow().bold(),
+            config.history_path.display(),
+            e,
+            fallback.display()
+        );
+        config.history_path = fallback;
+    }
+    
+    // Held for the whole session; the lock is released when the file is closed on exit
+    let _history_lock = if config.single_instance {
+        match lock_history(&config.history_path)? {
+            Some(lock) => Some(lock),
+            None => anyhow::bail!(
+                "another ai-agent session is already running against this history ({}). \
+                Set SINGLE_INSTANCE=false to start anyway.",
+                config.history_path.display()
+            ),
+        }
+    } else {
+        None
+    };
     
     // Initialize the agent
-    let agent = OpenAIAgent::new(config.clone());
+    let mut agent = OpenAIAgent::new(config.clone());
+    agent.validate_tools()?;
+    agent.on_tool_call(print_tool_call);
     
     // Initialize the conversation list
     let list_path = config.history_path.join("conversations.json");
     let mut conversation_list = ConversationList::load_from_file(&list_path).unwrap_or_else(|_| ConversationList::new());
     
-    // Initialize or load a conversation
-    let mut current_conversation = Conversation::new("New Conversation".to_string());
-    
-    // Add a system message
-    current_conversation.add_message(Message::system(
-        "You are an AI assistant with access to Context7 libraries. You can help users \
-        by providing documentation and assistance related to various programming libraries. \
-        To use a library, you'll first need to resolve its ID and then fetch its documentation.".to_string()
-    ));
+    // Initialize a conversation with the system message
+    let mut current_conversation = fresh_conversation(&config);
+    if let Some(path) = append_file {
+        let transcript = fs::read_to_string(path)?;
+        let messages = Conversation::messages_from_transcript(&transcript)
+            .map_err(|e| anyhow::anyhow!("Invalid transcript {}: {}", path.display(), e))?;
+        println!("Seeded the conversation with {} messages from {}", messages.len(), path.display());
+        for message in messages {
+            current_conversation.add_message(message);
+        }
+    }
+    config.remember_last_conversation(&current_conversation.id);
     
     // Initialize readline
     let mut rl = DefaultEditor::new()?;
+    let stash = Arc::new(Mutex::new(None));
+    rl.bind_sequence(KeyEvent::alt('s'), EventHandler::Conditional(Box::new(StashLine(stash.clone()))));
     
     // Display welcome message
     println!("{}", WELCOME_MESSAGE);
+    println!("{}", HELP_MESSAGE);
     
     // Try to start the MCP server, but don't fail if it can't start
-    if let Err(e) = mcp::ensure_mcp_server_running(&config).await {
-        println!("Note: Context7 MCP server could not be started: {}", e);
-        println!("Some functionality may be limited. Continuing without Context7 integration.");
+    if !config.mcp_enabled {
+        println!("Context7 MCP integration is disabled. Chatting without library documentation tools.");
+    } else if !config.provider().supports_tools {
+        println!("{} does not support tools. Chatting without library documentation tools.", config.provider().name);
+    } else if let Err(e) = mcp::ensure_mcp_servers_running(&config).await {
+        println!("Note: some MCP servers could not be started: {}", e);
+        println!("Some functionality may be limited. Continuing without their tools.");
+    }
+    if !docs_tools_available(&config) && config.use_prompt_without_docs_tools() {
+        current_conversation.set_system_prompt(config.system_prompt.clone());
     }
     
+    // Phrases for the thinking indicator, cycled on each request
+    let mut thinking_phrases: Vec<String> = config.thinking_message
+        .split('|')
+        .map(str::trim)
+        .filter(|phrase| !phrase.is_empty())
+        .map(str::to_string)
+        .collect();
+    if thinking_phrases.is_empty() {
+        thinking_phrases.push("Thinking".to_string());
+    }
+    let mut turn = 0;
+    let mut unsaved_exchanges = 0;
+    
+    // Images attached with `!image`, sent with the next message
+    let mut pending_parts: Vec<ContentPart> = Vec::new();
+    
+    // Conversation that was last warned about nearing the context window
+    let mut context_warned_for: Option<String> = None;
+    
+    // File that responses are copied to while `!tee` is active
+    let mut tee_path: Option<PathBuf> = None;
+    
     // Main REPL loop
     loop {
-        match rl.readline("You: ") {
+        // A stashed line comes back once the command typed in its place has run
+        let stashed = stash.lock().unwrap().take();
+        let you = if config.show_timestamps {
+            format!("[{}] You: ", short_time(Utc::now()))
+        } else {
+            "You: ".to_string()
+        };
+        let input = match &stashed {
+            Some(line) => rl.readline_with_initial(&you, (line, "")),
+            None => rl.readline(&you),
+        };
+        
+        match input {
             Ok(line) => {
                 let trimmed = line.trim();
+                let mut prompt = trimmed.to_string();
+                // The answer `!regenerate` replaces, restored if the new request fails
+                let mut replaced = None;
                 
                 // Handle commands
                 if trimmed.starts_with('!') {
-                    match trimmed {
+                    let (command, args) = trimmed
+                        .split_once(char::is_whitespace)
+                        .map(|(command, args)| (command, args.trim()))
+                        .unwrap_or((trimmed, ""));
+                    
+                    match command {
                         "!help" => {
                             println!("{}", HELP_MESSAGE);
                             continue;
@@ -84,24 +227,24 @@ pub async fn start_chat() -> Result<()> {
                             println!("Goodbye!");
                             
                             // Save the current conversation
-                            save_conversation(&mut current_conversation, &mut conversation_list, &config)?;
+                            save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
                             
                             // Try to stop the MCP server, but don't fail if it's not running
-                            let _ = mcp::stop_mcp_server().await;
+                            let _ = mcp::stop_mcp_servers().await;
                             
                             break;
                         },
                         "!new" => {
+                            if !confirm_discard(&mut rl, &current_conversation, &config)? {
+                                continue;
+                            }
+                            
                             // Save the current conversation
-                            save_conversation(&mut current_conversation, &mut conversation_list, &config)?;
+                            save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
                             
                             // Create a new conversation
-                            current_conversation = Conversation::new("New Conversation".to_string());
-                            current_conversation.add_message(Message::system(
-                                "You are an AI assistant with access to Context7 libraries. You can help users \
-                                by providing documentation and assistance related to various programming libraries. \
-                                To use a library, you'll first need to resolve its ID and then fetch its documentation.".to_string()
-                            ));
+                            current_conversation = fresh_conversation(&config);
+                            config.remember_last_conversation(&current_conversation.id);
                             
                             println!("Started a new conversation");
                             continue;
@@ -111,48 +254,585 @@ pub async fn start_chat() -> Result<()> {
                             continue;
                         },
                         "!load" => {
-                            println!("Enter conversation ID to load:");
-                            let id = rl.readline("ID: ")?;
-                            
-                            // Find the ID first, then clone it to avoid borrowing issues
-                            let found_id = conversation_list.conversations.iter()
-                                .find(|c| c.id == id)
-                                .map(|summary| (summary.id.clone(), summary.title.clone()));
+                            let query = if args.is_empty() {
+                                println!("Enter conversation ID, number or title to load:");
+                                rl.readline("ID: ")?.trim().to_string()
+                            } else {
+                                args.to_string()
+                            };
                             
-                            if let Some((conversation_id, title)) = found_id {
-                                let conv_path = config.history_path.join(format!("{}.json", conversation_id));
+                            if let Some(summary) = resolve_conversation(&mut rl, &conversation_list, &query)? {
+                                let conv_path = config.history_path.join(format!("{}.json", summary.id));
                                 match Conversation::load_from_file(&conv_path) {
                                     Ok(conversation) => {
                                         // Save the current conversation first
-                                        save_conversation(&mut current_conversation, &mut conversation_list, &config)?;
+                                        save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
                                         
                                         // Load the selected conversation
                                         current_conversation = conversation;
-                                        println!("Loaded conversation: {}", title);
+                                        config.remember_last_conversation(&current_conversation.id);
+                                        println!("Loaded conversation: {}", summary.title);
+                                        print_history(&current_conversation, &config);
                                     },
                                     Err(e) => {
                                         println!("Error loading conversation: {}", e);
                                     }
                                 }
+                            }
+                            continue;
+                        },
+                        "!rename" => {
+                            let title = if args.is_empty() {
+                                println!("Enter a new title for \"{}\":", current_conversation.title);
+                                rl.readline("Title: ")?.trim().to_string()
                             } else {
-                                println!("Conversation not found with ID: {}", id);
+                                args.to_string()
+                            };
+                            if title.is_empty() {
+                                println!("Title unchanged");
+                                continue;
+                            }
+                            
+                            current_conversation.title = title;
+                            if current_conversation.has_exchanges() {
+                                save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
+                            }
+                            println!("Renamed conversation to \"{}\"", current_conversation.title);
+                            continue;
+                        },
+                        "!delete" => {
+                            if args.is_empty() {
+                                println!("Usage: !delete <id|number>");
+                                continue;
+                            }
+                            
+                            // Deleting is permanent, so only unambiguous references are accepted
+                            let id = match find_conversation(&conversation_list, args) {
+                                Some(summary) => summary.id.clone(),
+                                // A file the index lost track of can still be removed by its full ID
+                                None if Uuid::parse_str(args).is_ok()
+                                    && config.history_path.join(format!("{}.json", args)).exists() => args.to_string(),
+                                None => {
+                                    println!("Conversation not found: {}", args);
+                                    continue;
+                                }
+                            };
+                            
+                            let conv_path = config.history_path.join(format!("{}.json", id));
+                            if let Err(e) = fs::remove_file(&conv_path) {
+                                if e.kind() != io::ErrorKind::NotFound {
+                                    println!("Error deleting conversation: {}", e);
+                                    continue;
+                                }
+                            }
+                            let _ = fs::remove_file(embeddings_path(&config.history_path, &id));
+                            let title = conversation_list.remove(&id).map(|summary| summary.title).unwrap_or_else(|| id.clone());
+                            if let Err(e) = conversation_list.save_to_file(&list_path) {
+                                error!("Failed to save conversation list: {}", e);
+                            }
+                            println!("Deleted conversation: {}", title);
+                            
+                            // The active conversation can't outlive its file, so continue in a fresh one
+                            if id == current_conversation.id {
+                                current_conversation = fresh_conversation(&config);
+                                config.remember_last_conversation(&current_conversation.id);
+                                println!("Started a new conversation");
                             }
                             continue;
                         },
                         "!clear" => {
+                            if !confirm_discard(&mut rl, &current_conversation, &config)? {
+                                continue;
+                            }
+                            
                             // Create a new conversation with the same ID
                             let id = current_conversation.id.clone();
-                            current_conversation = Conversation::new("New Conversation".to_string());
+                            current_conversation = fresh_conversation(&config);
                             current_conversation.id = id;
-                            current_conversation.add_message(Message::system(
-                                "You are an AI assistant with access to Context7 libraries. You can help users \
-                                by providing documentation and assistance related to various programming libraries. \
-                                To use a library, you'll first need to resolve its ID and then fetch its documentation.".to_string()
-                            ));
                             
                             println!("Conversation cleared");
                             continue;
                         },
+                        "!replay" => {
+                            if args.is_empty() {
+                                println!("Usage: !replay <model>");
+                                continue;
+                            }
+                            if !current_conversation.messages.iter().any(|m| matches!(m.role, Role::User)) {
+                                println!("Nothing to replay in the current conversation");
+                                continue;
+                            }
+                            
+                            let mut replay = replay_conversation(&current_conversation, args, &config).await;
+                            save_conversation(&agent, &mut replay, &mut conversation_list, &config).await?;
+                            println!("Saved replay as \"{}\" ({})", replay.title, replay.id);
+                            continue;
+                        },
+                        "!compare" => {
+                            let mut parts = args.splitn(3, char::is_whitespace);
+                            let (Some(model_a), Some(model_b)) = (parts.next().filter(|m| !m.is_empty()), parts.next()) else {
+                                println!("Usage: !compare <model-a> <model-b> [question]");
+                                continue;
+                            };
+                            let question = match parts.next().map(str::trim).filter(|q| !q.is_empty()) {
+                                Some(question) => question.to_string(),
+                                None => rl.readline("Question: ")?.trim().to_string(),
+                            };
+                            if question.is_empty() {
+                                println!("Nothing to compare");
+                                continue;
+                            }
+                            
+                            // Neither answer touches the conversation until one is picked
+                            let mut candidate = current_conversation.clone();
+                            candidate.add_message(Message::user(question.clone()));
+                            let agent_a = OpenAIAgent::new(Config { openai_api_model: model_a.to_string(), ..config.clone() });
+                            let agent_b = OpenAIAgent::new(Config { openai_api_model: model_b.to_string(), ..config.clone() });
+                            
+                            println!("Asking {} and {}...", model_a, model_b);
+                            let (answer_a, answer_b) = futures::join!(agent_a.chat(&candidate), agent_b.chat(&candidate));
+                            let describe = |answer: &Result<Message>| match answer {
+                                Ok(message) => message.content.clone(),
+                                Err(e) => format!("Error: {}", e),
+                            };
+                            print_side_by_side((model_a, &describe(&answer_a)), (model_b, &describe(&answer_b)));
+                            
+                            let choice = rl.readline("Keep which answer? [1/2, Enter for neither]: ")?;
+                            let (kept, model, answered_by) = match choice.trim() {
+                                "1" => (answer_a.ok(), model_a, &agent_a),
+                                "2" => (answer_b.ok(), model_b, &agent_b),
+                                _ => (None, "", &agent),
+                            };
+                            match kept {
+                                Some(_) if config.dry_run => println!("Nothing was sent, so no answer was kept"),
+                                Some(response) => {
+                                    current_conversation.add_message(Message::user(question));
+                                    add_response(&mut current_conversation, answered_by, response);
+                                    save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
+                                    println!("Kept the answer from {}", model);
+                                },
+                                None => println!("No answer was kept"),
+                            }
+                            continue;
+                        },
+                        "!config" => {
+                            let changed = settings::edit_settings(&mut rl, &mut config)?;
+                            if !changed.is_empty() {
+                                // The new settings apply from the next request on
+                                agent.reconfigure(config.clone());
+                                if changed.iter().any(|name| name == "system_prompt") {
+                                    current_conversation.set_system_prompt(config.system_prompt.clone());
+                                }
+                                println!("Settings applied");
+                            }
+                            continue;
+                        },
+                        "!export-all" => {
+                            // Include the current conversation in the archive
+                            save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
+                            
+                            // System instructions may be sensitive, so they stay out unless asked for
+                            let (include_system, path) = take_flag(args, "--include-system");
+                            let path = Some(path)
+                                .filter(|path| !path.is_empty())
+                                .map(PathBuf::from)
+                                .unwrap_or_else(export::default_archive_path);
+                            match export::export_all(&config, &conversation_list, &path, include_system) {
+                                Ok(size) => println!(
+                                    "Exported {} conversations to {} ({} bytes)",
+                                    conversation_list.conversations.len(),
+                                    path.display(),
+                                    size
+                                ),
+                                Err(e) => println!("Failed to export history: {}", e),
+                            }
+                            continue;
+                        },
+                        "!params" => {
+                            if args == "clear" {
+                                current_conversation.params = None;
+                                println!("Cleared this conversation's parameters");
+                            } else if !args.is_empty() {
+                                let mut params = current_conversation.params.clone().unwrap_or_default();
+                                let applied = args.split_whitespace().try_for_each(|setting| match setting.split_once('=') {
+                                    Some((name, value)) => params.set(name, value),
+                                    None => Err(anyhow::anyhow!("Expected name=value, got '{}'", setting)),
+                                });
+                                match applied {
+                                    Ok(()) => current_conversation.params = Some(params).filter(|p| !p.is_empty()),
+                                    Err(e) => {
+                                        println!("{}", e);
+                                        continue;
+                                    }
+                                }
+                            }
+                            
+                            print_params(&current_conversation, &config);
+                            continue;
+                        },
+                        "!export" => {
+                            // A leading ID or list number picks the conversation; otherwise the whole
+                            // argument is the path for the current one, spaces included
+                            let (id, path) = match args.split_once(char::is_whitespace) {
+                                Some((id, path)) if id == current_conversation.id => (None, path.trim()),
+                                Some((id, path)) => match find_conversation(&conversation_list, id) {
+                                    Some(summary) => (Some(summary.id.clone()), path.trim()),
+                                    None => (None, args),
+                                },
+                                None => (None, args),
+                            };
+                            if path.is_empty() {
+                                println!("Usage: !export [id|number] <path.md>");
+                                continue;
+                            }
+                            
+                            let conversation = match id {
+                                Some(id) if id != current_conversation.id => {
+                                    match Conversation::load_from_file(&config.history_path.join(format!("{}.json", id))) {
+                                        Ok(conversation) => conversation,
+                                        Err(e) => {
+                                            println!("Error loading conversation: {}", e);
+                                            continue;
+                                        }
+                                    }
+                                },
+                                _ => current_conversation.clone(),
+                            };
+                            match export::export_markdown(&conversation, Path::new(path)) {
+                                Ok(()) => println!("Exported \"{}\" to {}", conversation.title, path),
+                                Err(e) => println!("Failed to export conversation: {}", e),
+                            }
+                            continue;
+                        },
+                        "!export-code" => {
+                            if args.is_empty() {
+                                println!("Usage: !export-code <dir>");
+                                continue;
+                            }
+                            match export::export_code(&current_conversation, Path::new(args)) {
+                                Ok(paths) if paths.is_empty() => println!("No code blocks in this conversation's responses"),
+                                Ok(paths) => {
+                                    for path in &paths {
+                                        println!("  {}", path.display());
+                                    }
+                                    println!("Exported {} code blocks to {}", paths.len(), args);
+                                },
+                                Err(e) => println!("Failed to export code: {}", e),
+                            }
+                            continue;
+                        },
+                        "!edit" => {
+                            let last_user = current_conversation.messages
+                                .iter()
+                                .rposition(|m| matches!(m.role, Role::User));
+                            let Some(index) = last_user.filter(|index| *index > 0) else {
+                                println!("No question to edit yet");
+                                continue;
+                            };
+                            
+                            let original = current_conversation.messages[index].clone();
+                            let edited = rl.readline_with_initial("You: ", (original.content.as_str(), ""))?.trim().to_string();
+                            if edited.is_empty() {
+                                println!("Edit cancelled");
+                                continue;
+                            }
+                            
+                            // Drop the old question and everything after it, then send the edited one
+                            // with the images it had, ahead of any attached since
+                            current_conversation.truncate_to(index - 1);
+                            pending_parts.splice(0..0, original.parts);
+                            prompt = edited;
+                        },
+                        "!fork" => {
+                            let last = current_conversation.messages.len().saturating_sub(1);
+                            let index = if args.is_empty() {
+                                last
+                            } else {
+                                match args.parse::<usize>() {
+                                    Ok(index) if index <= last => index,
+                                    _ => {
+                                        println!("Usage: !fork [index], where index is 0-{} (0 is the system prompt)", last);
+                                        continue;
+                                    }
+                                }
+                            };
+                            
+                            // The original is saved as it is and left alone from here on
+                            save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
+                            current_conversation = current_conversation.fork_at(index);
+                            save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
+                            config.remember_last_conversation(&current_conversation.id);
+                            println!("Switched to \"{}\" with the first {} messages", current_conversation.title, index + 1);
+                            continue;
+                        },
+                        "!regenerate" => {
+                            match current_conversation.pop_last_response() {
+                                Some(previous) => replaced = Some(previous),
+                                None => {
+                                    println!("The last message is not a response, so there is nothing to regenerate");
+                                    continue;
+                                }
+                            }
+                        },
+                        "!undo" => {
+                            let last_user = current_conversation.messages
+                                .iter()
+                                .rposition(|m| matches!(m.role, Role::User));
+                            match last_user {
+                                Some(index) if index > 0 => {
+                                    let removed = current_conversation.truncate_to(index - 1);
+                                    println!("Removed the last exchange ({} messages)", removed);
+                                },
+                                _ => println!("Nothing to undo"),
+                            }
+                            continue;
+                        },
+                        "!setsystem" => {
+                            if args.is_empty() {
+                                println!("Usage: !setsystem <instructions>");
+                            } else {
+                                current_conversation.set_system_prompt(args.to_string());
+                                println!("System prompt replaced");
+                            }
+                            continue;
+                        },
+                        "!addsystem" => {
+                            if args.is_empty() {
+                                println!("Usage: !addsystem <instructions>");
+                            } else {
+                                current_conversation.add_message(Message::system(args.to_string()));
+                                println!("Added system instructions ({} layers)", current_conversation.system_messages().count());
+                            }
+                            continue;
+                        },
+                        "!reload" => {
+                            if !config.mcp_enabled {
+                                println!("MCP integration is disabled; enable it with MCP_ENABLED=true");
+                                continue;
+                            }
+                            let retried = mcp::retry_failed_mcp_servers();
+                            match mcp::ensure_mcp_servers_running(&config).await {
+                                Ok(()) if retried == 0 => println!("No MCP servers had failed to start"),
+                                Ok(()) => println!("MCP servers running: {}", mcp::running_mcp_servers().join(", ")),
+                                Err(e) => println!("Some MCP servers are still unavailable: {}", e),
+                            }
+                            continue;
+                        },
+                        "!resolve" | "!docs" => {
+                            if args.is_empty() {
+                                println!("Usage: !resolve <library> or !docs <library-id> [topic]");
+                                continue;
+                            }
+                            if !config.mcp_enabled {
+                                println!("Context7 MCP integration is disabled; enable it with MCP_ENABLED=true");
+                                continue;
+                            }
+                            if let Err(e) = mcp::ensure_mcp_servers_running(&config).await {
+                                // Another server failing doesn't stop the Context7 lookup
+                                println!("Some MCP servers are unavailable: {}", e);
+                            }
+                            
+                            if command == "!resolve" {
+                                match mcp::resolve_library_id(args.to_string()).await {
+                                    Ok(matches) => println!("{}", sanitize_terminal_output(&matches)),
+                                    Err(e) => println!("Context7 lookup failed: {}", e),
+                                }
+                            } else {
+                                let (id, topic) = match args.split_once(char::is_whitespace) {
+                                    Some((id, topic)) => (id, Some(topic.trim().to_string())),
+                                    None => (args, None),
+                                };
+                                match mcp::get_library_docs(id.to_string(), None, topic).await {
+                                    Ok(docs) => println!("{}", sanitize_terminal_output(&docs)),
+                                    Err(e) => println!("Context7 lookup failed: {}", e),
+                                }
+                            }
+                            continue;
+                        },
+                        "!last" => {
+                            match current_conversation.messages.iter().rev().find(|m| matches!(m.role, Role::Assistant)) {
+                                Some(response) => println!("{} {}", ai_label(response.created_at, &config), styled_response(sanitize_terminal_output(&response.content), &config)),
+                                None => println!("No response in this conversation yet"),
+                            }
+                            continue;
+                        },
+                        "!retry-last" => {
+                            show_thinking_indicator(&thinking_phrases[0])?;
+                            let result = agent.retry_last().await;
+                            clear_thinking_indicator()?;
+                            
+                            match result {
+                                Ok(response) => {
+                                    print_response(&response, &config);
+                                    // Only an unanswered question takes the answer; otherwise it is just for comparison
+                                    if config.dry_run {
+                                        continue;
+                                    }
+                                    if current_conversation.messages.last().is_some_and(|m| matches!(m.role, Role::User)) {
+                                        add_response(&mut current_conversation, &agent, response);
+                                        save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
+                                    } else {
+                                        println!("{}", "(shown for comparison, not added to the conversation)".dimmed());
+                                    }
+                                },
+                                Err(e) => println!("{} Error: {}", "AI:".red().bold(), e),
+                            }
+                            continue;
+                        },
+                        "!tokens" => {
+                            print_usage(&current_conversation, &agent.session_usage(), &config);
+                            continue;
+                        },
+                        "!raw" => {
+                            match agent.last_response() {
+                                Some(raw) => println!("{}", serde_json::to_string_pretty(&raw)?),
+                                None => println!("No API response received yet in this session"),
+                            }
+                            continue;
+                        },
+                        "!find" => {
+                            if args.is_empty() {
+                                println!("Usage: !find <query>");
+                                continue;
+                            }
+                            
+                            // Pick up this conversation's latest messages too
+                            save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
+                            println!("{}", "Searching conversations...".dimmed());
+                            match semantic_search(&agent, &config, args, FIND_RESULTS).await {
+                                Ok(hits) if hits.is_empty() => println!("No messages to search yet"),
+                                Ok(hits) => {
+                                    for hit in hits {
+                                        let role = match hit.role {
+                                            Role::User => "You",
+                                            Role::Tool => "Tool",
+                                            _ => "AI",
+                                        };
+                                        let excerpt = sanitize_terminal_output(&hit.content).split_whitespace().join(" ");
+                                        println!(
+                                            "{:.2}  {} {}",
+                                            hit.score,
+                                            hit.title.bold(),
+                                            format!("({}, message {})", hit.conversation_id, hit.index).dimmed()
+                                        );
+                                        println!("      {}: {}", role, truncate_chars(&excerpt, 100));
+                                    }
+                                },
+                                Err(e) => println!("Search failed: {}", e),
+                            }
+                            continue;
+                        },
+                        "!reference" => {
+                            let (query, mode) = match args.rsplit_once(char::is_whitespace) {
+                                Some((query, mode)) if ReferenceMode::from_name(mode).is_some() => {
+                                    (query.trim(), ReferenceMode::from_name(mode).unwrap_or_default())
+                                },
+                                _ => (args, config.reference_mode),
+                            };
+                            if query.is_empty() {
+                                println!("Usage: !reference <id, number or title> [full|summary]");
+                                continue;
+                            }
+                            
+                            if let Some(summary) = resolve_conversation(&mut rl, &conversation_list, query)? {
+                                let conv_path = config.history_path.join(format!("{}.json", summary.id));
+                                let referenced = match Conversation::load_from_file(&conv_path) {
+                                    Ok(conversation) => conversation,
+                                    Err(e) => {
+                                        println!("Error loading conversation: {}", e);
+                                        continue;
+                                    }
+                                };
+                                
+                                match reference_block(&agent, &referenced, mode).await {
+                                    Ok(block) => {
+                                        let tokens = count_tokens(&config.openai_api_model, &block);
+                                        current_conversation.add_message(Message::system(block));
+                                        println!(
+                                            "Added \"{}\" as reference (about {} tokens; the conversation now uses about {} of {})",
+                                            referenced.title,
+                                            tokens,
+                                            current_conversation.total_tokens_estimate(&config.openai_api_model),
+                                            config.context_window_tokens
+                                        );
+                                    },
+                                    Err(e) => println!("Error summarizing conversation: {}", e),
+                                }
+                            }
+                            continue;
+                        },
+                        "!image" => {
+                            if args.is_empty() {
+                                println!("Usage: !image <path or URL>");
+                                continue;
+                            }
+                            
+                            match image_part(args) {
+                                Ok(part) => {
+                                    println!("Attached {} ({}); it will be sent with your next message", args, part.describe());
+                                    pending_parts.push(part);
+                                },
+                                Err(e) => println!("Error attaching image: {}", e),
+                            }
+                            continue;
+                        },
+                        "!tee" => {
+                            match args {
+                                "" => match &tee_path {
+                                    Some(path) => println!("Copying responses to {}", path.display()),
+                                    None => println!("Usage: !tee <file> or !tee off"),
+                                },
+                                "off" => {
+                                    if let Some(path) = tee_path.take() {
+                                        println!("Stopped copying responses to {}", path.display());
+                                    }
+                                },
+                                path => {
+                                    let path = config::expand_home(Path::new(path));
+                                    println!("Copying responses to {}", path.display());
+                                    tee_path = Some(path);
+                                },
+                            }
+                            continue;
+                        },
+                        "!timestamps" => {
+                            match args {
+                                "on" => config.show_timestamps = true,
+                                "off" => config.show_timestamps = false,
+                                "" => config.show_timestamps = !config.show_timestamps,
+                                _ => {
+                                    println!("Usage: !timestamps [on|off]");
+                                    continue;
+                                },
+                            }
+                            println!("Timestamps {}", if config.show_timestamps { "on" } else { "off" });
+                            continue;
+                        },
+                        "!whoami" => {
+                            print_effective_config(&config);
+                            continue;
+                        },
+                        "!cls" | "!clear-screen" => {
+                            // Only the terminal is cleared; the conversation is kept
+                            rl.clear_screen()?;
+                            continue;
+                        },
+                        "!edit-prompt" => {
+                            match compose_in_editor() {
+                                Ok(Some(text)) => {
+                                    println!("{} {}", "You:".bold(), text);
+                                    prompt = text;
+                                },
+                                Ok(None) => {
+                                    println!("Editor closed without a prompt, nothing sent");
+                                    continue;
+                                },
+                                Err(e) => {
+                                    println!("Failed to open editor: {}", e);
+                                    continue;
+                                }
+                            }
+                        },
                         _ => {
                             println!("Unknown command. Type !help for available commands.");
                             continue;
@@ -160,58 +840,121 @@ pub async fn start_chat() -> Result<()> {
                     }
                 }
                 
-                // Skip empty lines
-                if trimmed.is_empty() {
-                    continue;
+                // A regenerated answer responds to the question already in the conversation
+                if replaced.is_none() {
+                    // Skip empty lines
+                    if prompt.is_empty() {
+                        continue;
+                    }
+                    
+                    // Add user message, with any images attached since the last one
+                    let mut user_message = Message::user(prompt);
+                    user_message.parts = std::mem::take(&mut pending_parts);
+                    current_conversation.add_message(user_message);
                 }
                 
-                // Add user message
-                let user_message = Message::user(trimmed.to_string());
-                current_conversation.add_message(user_message);
+                // Fold the oldest messages into a summary once the conversation outgrows its budget
+                if config.summarize_after_tokens > 0
+                    && !config.dry_run
+                    && current_conversation.total_tokens_estimate(&config.openai_api_model) > config.summarize_after_tokens
+                {
+                    match agent.summarize_history(&mut current_conversation, config.summary_keep_recent.max(1)).await {
+                        Ok(0) => {},
+                        Ok(count) => println!("{}", format!("Summarized {} earlier messages to save context", count).dimmed()),
+                        Err(e) => println!("{} Could not summarize earlier messages: {}", "Warning:".yellow().bold(), e),
+                    }
+                }
                 
                 // Show thinking indicator
-                print!("AI: Thinking");
-                io::stdout().flush()?;
+                let phrase = &thinking_phrases[turn % thinking_phrases.len()];
+                turn += 1;
+                show_thinking_indicator(phrase)?;
                 
-                // Get response from agent
-                match agent.chat(&current_conversation).await {
+                // Get response from agent, printing it as it arrives when streaming
+                let result = if config.stream {
+                    stream_response(&agent, &current_conversation, &config, tee_path.as_deref()).await
+                } else {
+                    agent.chat(&current_conversation).await
+                };
+                match result {
                     Ok(response) => {
-                        // Clear the thinking indicator
-                        print!("\r");
-                        for _ in 0.."AI: Thinking".len() {
-                            print!(" ");
+                        if !config.stream {
+                            clear_thinking_indicator()?;
+                            
+                            // Print the response
+                            print_response(&response, &config);
+                            if let Some(path) = &tee_path {
+                                if let Err(e) = tee_response(path, &response) {
+                                    println!("{} Failed to write to {}: {}", "Warning:".yellow().bold(), path.display(), e);
+                                }
+                            }
                         }
-                        print!("\r");
-                        io::stdout().flush()?;
                         
-                        // Print the response
-                        println!("{} {}", "AI:".green().bold(), response.content);
+                        // A dry run sent nothing: leave the conversation as it was before the question
+                        if config.dry_run {
+                            agent.take_tool_turns();
+                            match replaced {
+                                Some(previous) => current_conversation.messages.extend(previous),
+                                None => {
+                                    current_conversation.messages.pop();
+                                },
+                            }
+                            continue;
+                        }
                         
                         // Add the response to the conversation
-                        current_conversation.add_message(response);
+                        add_response(&mut current_conversation, &agent, response);
+                        
+                        // Nudge once per conversation when it nears the model's context window
+                        let used = current_conversation.total_tokens_estimate(&config.openai_api_model);
+                        if used * 4 >= config.context_window_tokens * 3
+                            && context_warned_for.as_deref() != Some(current_conversation.id.as_str())
+                        {
+                            println!("{}", format!(
+                                "This conversation uses about {} of {} context tokens. Consider !new to start fresh.",
+                                used, config.context_window_tokens
+                            ).yellow());
+                            context_warned_for = Some(current_conversation.id.clone());
+                        }
                         
-                        // Auto-save the conversation after each exchange
-                        let conv_path = config.history_path.join(format!("{}.json", current_conversation.id));
-                        if let Err(e) = current_conversation.save_to_file(&conv_path) {
-                            error!("Failed to save conversation: {}", e);
+                        // Auto-save the conversation every `autosave_every` exchanges; switching
+                        // conversations and exiting always save
+                        unsaved_exchanges += 1;
+                        if unsaved_exchanges >= config.autosave_every.max(1) {
+                            unsaved_exchanges = 0;
+                            let conv_path = config.history_path.join(format!("{}.json", current_conversation.id));
+                            if let Err(e) = current_conversation.save_to_file(&conv_path) {
+                                error!("Failed to save conversation: {}", e);
+                            }
+                            
+                            // Update the conversation list
+                            conversation_list.add_conversation(&current_conversation);
+                            if let Err(e) = conversation_list.save_to_file(&list_path) {
+                                error!("Failed to save conversation list: {}", e);
+                            }
                         }
                         
-                        // Update the conversation list
-                        conversation_list.add_conversation(&current_conversation);
-                        if let Err(e) = conversation_list.save_to_file(&list_path) {
-                            error!("Failed to save conversation list: {}", e);
+                        // Roll over to a fresh conversation once the configured length is reached
+                        let max_messages = config.max_messages_per_conversation;
+                        let exchanged = current_conversation.messages.iter().filter(|m| !matches!(m.role, Role::System)).count();
+                        if max_messages > 0 && exchanged >= max_messages {
+                            save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
+                            println!("{}", format!(
+                                "Conversation reached {} messages and was saved as \"{}\". Continuing in a new conversation.",
+                                exchanged, current_conversation.title
+                            ).yellow());
+                            current_conversation = rolled_over(&agent, &current_conversation, &config).await;
+                            config.remember_last_conversation(&current_conversation.id);
                         }
                     },
                     Err(e) => {
-                        // Clear the thinking indicator
-                        print!("\r");
-                        for _ in 0.."AI: Thinking".len() {
-                            print!(" ");
-                        }
-                        print!("\r");
-                        io::stdout().flush()?;
+                        clear_thinking_indicator()?;
                         
                         println!("{} Error: {}", "AI:".red().bold(), e);
+                        if let Some(previous) = replaced {
+                            current_conversation.messages.extend(previous);
+                            println!("Kept the previous response");
+                        }
                     }
                 }
             },
@@ -222,10 +965,10 @@ pub async fn start_chat() -> Result<()> {
                 println!("CTRL-D pressed, exiting...");
                 
                 // Save the current conversation
-                save_conversation(&mut current_conversation, &mut conversation_list, &config)?;
+                save_conversation(&agent, &mut current_conversation, &mut conversation_list, &config).await?;
                 
                 // Try to stop the MCP server, but don't fail if it's not running
-                let _ = mcp::stop_mcp_server().await;
+                let _ = mcp::stop_mcp_servers().await;
                 
                 break;
             },
@@ -239,26 +982,27 @@ pub async fn start_chat() -> Result<()> {
     Ok(())
 }
 
-fn save_conversation(
+/// Adds an answer to the conversation, preceded by the tool calls and results that led to it.
+pub(super) fn add_response(conversation: &mut Conversation, agent: &OpenAIAgent, response: Message) {
+    for message in agent.take_tool_turns() {
+        conversation.add_message(message);
+    }
+    conversation.add_message(response);
+}
+
+pub(super) async fn save_conversation(
+    agent: &OpenAIAgent,
     conversation: &mut Conversation,
     conversation_list: &mut ConversationList,
     config: &Config
 ) -> Result<()> {
-    // Don't save empty conversations
-    if conversation.messages.len() <= 1 {
+    // Don't save conversations that only hold system instructions, nor anything during a dry run
+    if !conversation.has_exchanges() || config.dry_run {
         return Ok(());
     }
     
-    // Set a better title based on the first user message
     if conversation.title == "New Conversation" {
-        if let Some(first_user_msg) = conversation.messages.iter().find(|m| matches!(m.role, Role::User)) {
-            let title = if first_user_msg.content.len() > 50 {
-                format!("{}...", &first_user_msg.content[..47])
-            } else {
-                first_user_msg.content.clone()
-            };
-            conversation.title = title;
-        }
+        conversation.title = auto_title(agent, conversation, config.title_strategy).await;
     }
     
     // Save the conversation
@@ -266,13 +1010,38 @@ fn save_conversation(
     conversation.save_to_file(&conv_path)?;
     
     // Update the conversation list
-    conversation_list.add_conversation(&conversation);
+    conversation_list.add_conversation(conversation);
     let list_path = config.history_path.join("conversations.json");
     conversation_list.save_to_file(&list_path)?;
     
     Ok(())
 }
 
+/// Names an untitled conversation according to the configured strategy. A title
+/// from the model falls back to the first message if the request fails.
+async fn auto_title(agent: &OpenAIAgent, conversation: &Conversation, strategy: TitleStrategy) -> String {
+    let first_message = || {
+        let first_user_msg = conversation.messages.iter().find(|m| matches!(m.role, Role::User));
+        match first_user_msg {
+            Some(message) if message.content.chars().count() > 50 => format!("{}...", truncate_chars(&message.content, 47)),
+            Some(message) => message.content.clone(),
+            None => conversation.title.clone(),
+        }
+    };
+    
+    match strategy {
+        TitleStrategy::FirstMessage => first_message(),
+        TitleStrategy::Timestamp => format!(
+            "Conversation {}",
+            conversation.created_at.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+        ),
+        TitleStrategy::Llm => match agent.suggest_title(conversation).await {
+            Ok(title) if !title.is_empty() => title,
+            _ => first_message(),
+        },
+    }
+}
+
 fn list_conversations(conversation_list: &ConversationList) {
     if conversation_list.conversations.is_empty() {
         println!("No saved conversations");
@@ -280,19 +1049,631 @@ fn list_conversations(conversation_list: &ConversationList) {
     }
     
     println!("{}", "Saved Conversations:".bold());
-    println!("{}", "─".repeat(80));
-    println!("{:<36} │ {:<30} │ {:<10}", "ID", "Title", "Messages");
-    println!("{}", "─".repeat(80));
+    println!("{}", "─".repeat(86));
+    println!("{:>3} │ {:<36} │ {:<30} │ {:<10}", "#", "ID", "Title", "Messages");
+    println!("{}", "─".repeat(86));
     
     for (i, summary) in conversation_list.conversations.iter().enumerate() {
-        println!("{:<36} │ {:<30} │ {:<10}",
+        println!("{:>3} │ {:<36} │ {:<30} │ {:<10}",
+            i + 1,
             summary.id,
-            if summary.title.len() > 28 { format!("{}...", &summary.title[..25]) } else { summary.title.clone() },
+            if summary.title.chars().count() > 28 { format!("{}...", truncate_chars(&summary.title, 25)) } else { summary.title.clone() },
             summary.message_count
         );
         
         if i < conversation_list.conversations.len() - 1 {
-            println!("{}", "─".repeat(80));
+            println!("{}", "─".repeat(86));
         }
     }
-} 
\ No newline at end of file
+}
+
+/// Finds a conversation by exact ID, by its number in `!list`, or by a unique ID prefix.
+fn find_conversation<'a>(conversation_list: &'a ConversationList, query: &str) -> Option<&'a ConversationSummary> {
+    let conversations = &conversation_list.conversations;
+    
+    if let Some(summary) = conversations.iter().find(|c| c.id == query) {
+        return Some(summary);
+    }
+    
+    if let Ok(index) = query.parse::<usize>() {
+        if let Some(summary) = index.checked_sub(1).and_then(|i| conversations.get(i)) {
+            return Some(summary);
+        }
+    }
+    
+    let prefixed: Vec<_> = conversations.iter().filter(|c| c.id.starts_with(query)).collect();
+    if prefixed.len() == 1 {
+        return Some(prefixed[0]);
+    }
+    
+    None
+}
+
+/// Resolves a `!load` query to a saved conversation. Exact IDs are the fast path,
+/// followed by `!list` numbers, unique ID prefixes and finally a fuzzy title match
+/// that the user confirms.
+fn resolve_conversation(
+    rl: &mut DefaultEditor,
+    conversation_list: &ConversationList,
+    query: &str
+) -> Result<Option<ConversationSummary>> {
+    if query.is_empty() {
+        return Ok(None);
+    }
+    
+    if let Some(summary) = find_conversation(conversation_list, query) {
+        return Ok(Some(summary.clone()));
+    }
+    
+    let matches: Vec<_> = conversation_list.rank_by_title(query, 0.7).into_iter().take(5).collect();
+    if matches.is_empty() {
+        println!("Conversation not found: {}", query);
+        return Ok(None);
+    }
+    
+    println!("Closest matches:");
+    for (i, (summary, score)) in matches.iter().enumerate() {
+        println!("  {}. {} {}", i + 1, summary.title, format!("({:.0}%)", score * 100.0).dimmed());
+    }
+    
+    let choice = rl.readline("Load which? [number, Enter to cancel]: ")?;
+    let selected = choice.trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| matches.get(i))
+        .map(|(summary, _)| (*summary).clone());
+    
+    Ok(selected)
+}
+
+/// Removes `flag` from the start or end of `args`, returning whether it was given and
+/// the rest of the arguments, which may contain spaces.
+fn take_flag<'a>(args: &'a str, flag: &str) -> (bool, &'a str) {
+    if args == flag {
+        return (true, "");
+    }
+    
+    let rest = args.strip_prefix(flag).filter(|rest| rest.starts_with(char::is_whitespace))
+        .or_else(|| args.strip_suffix(flag).filter(|rest| rest.ends_with(char::is_whitespace)));
+    match rest {
+        Some(rest) => (true, rest.trim()),
+        None => (false, args),
+    }
+}
+
+/// Checks that `dir` exists (creating it if needed) and accepts new files.
+fn probe_writable(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".write-test-{}", Uuid::new_v4()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+/// Takes an advisory lock on `.lock` in the history directory so two sessions don't
+/// interleave writes to `conversations.json`. Returns `None` if another session holds it.
+fn lock_history(dir: &Path) -> Result<Option<std::fs::File>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(".lock"))?;
+    
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+        Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Asks before a command clears the current conversation from the session.
+/// Returns `true` straight away when there is nothing to lose or prompts are disabled.
+fn confirm_discard(rl: &mut DefaultEditor, conversation: &Conversation, config: &Config) -> Result<bool> {
+    let count = conversation.messages.iter().filter(|m| !matches!(m.role, Role::System)).count();
+    if count == 0 || !config.confirm_discard {
+        return Ok(true);
+    }
+    
+    let answer = rl.readline(&format!("This will clear {} messages. Continue? [y/N] ", count))?;
+    let confirmed = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+    if !confirmed {
+        println!("Cancelled");
+    }
+    Ok(confirmed)
+}
+
+/// Whether the Context7 documentation tools can be offered in this session.
+pub(super) fn docs_tools_available(config: &Config) -> bool {
+    config.mcp_enabled
+        && config.provider().supports_tools
+        && mcp::running_mcp_servers().iter().any(|name| name == mcp::CONTEXT7)
+}
+
+/// Starts the conversation that continues `previous` once it hit its length limit. It keeps
+/// the system instructions in effect, including `!setsystem`/`!addsystem` changes, and
+/// with `rollover_summary` adds a summary of `previous` in place of its messages.
+async fn rolled_over(agent: &OpenAIAgent, previous: &Conversation, config: &Config) -> Conversation {
+    let mut conversation = Conversation::new("New Conversation".to_string());
+    // Summaries of earlier messages describe the old conversation, not the new one
+    for message in previous.system_messages().filter(|m| m.summarized.is_none()) {
+        conversation.add_message(Message::system(message.content.clone()));
+    }
+    
+    if config.rollover_summary {
+        match agent.summarize(&previous.transcript()).await {
+            Ok(summary) => {
+                let summary = format!("Summary of the previous conversation, \"{}\":\n{}", previous.title, summary);
+                conversation.add_message(Message::system(summary));
+                println!("{}", "Carried over a summary of the previous conversation".dimmed());
+            },
+            Err(e) => println!("{} Could not summarize the previous conversation: {}", "Warning:".yellow().bold(), e),
+        }
+    }
+    conversation
+}
+
+/// Starts an untitled conversation seeded with the configured system prompt and its layers.
+pub(super) fn fresh_conversation(config: &Config) -> Conversation {
+    let mut conversation = Conversation::new("New Conversation".to_string());
+    conversation.add_message(Message::system(config.system_prompt.clone()));
+    for layer in &config.extra_system_prompts {
+        conversation.add_message(Message::system(layer.clone()));
+    }
+    conversation
+}
+
+/// Re-asks every user turn of `conversation` against `model` in a fresh conversation,
+/// stopping at the first failed request.
+async fn replay_conversation(conversation: &Conversation, model: &str, config: &Config) -> Conversation {
+    let replay_agent = OpenAIAgent::new(Config {
+        openai_api_model: model.to_string(),
+        ..config.clone()
+    });
+    
+    let mut replay = Conversation::new(format!("Replay ({}) of {}", model, conversation.title));
+    for message in conversation.messages.iter().filter(|m| matches!(m.role, Role::System)) {
+        replay.add_message(Message::system(message.content.clone()));
+    }
+    
+    let user_turns: Vec<&Message> = conversation.messages
+        .iter()
+        .filter(|m| matches!(m.role, Role::User))
+        .collect();
+    
+    for (i, turn) in user_turns.iter().enumerate() {
+        println!("{} {}", format!("[{}/{}] You:", i + 1, user_turns.len()).bold(), turn.content);
+        replay.add_message(Message::user(turn.content.clone()));
+        
+        match replay_agent.chat(&replay).await {
+            Ok(response) => {
+                print_response(&response, config);
+                add_response(&mut replay, &replay_agent, response);
+            },
+            Err(e) => {
+                println!("{} Error: {}", "AI:".red().bold(), e);
+                break;
+            }
+        }
+    }
+    
+    replay
+}
+
+fn print_effective_config(config: &Config) {
+    let provider = config.provider().name;
+    let config_file = Config::file_path();
+    let mcp_status = if !config.mcp_enabled {
+        "disabled".to_string()
+    } else {
+        match mcp::running_mcp_servers() {
+            running if running.is_empty() => "not running".to_string(),
+            running => format!("running ({})", running.join(", ")),
+        }
+    };
+    
+    println!("{}", "Effective configuration:".bold());
+    println!("  {:<14} {}", "model", config.openai_api_model);
+    println!("  {:<14} {}", "base_url", config.openai_api_base_url);
+    println!("  {:<14} {}", "provider", provider);
+    println!("  {:<14} {}", "api_key", config.masked_api_key());
+    println!("  {:<14} {}", "history_path", config.history_path.display());
+    println!("  {:<14} {}", "mcp", mcp_status);
+    println!("  {:<14} {} ({})", "config_file", config_file.display(), if config_file.exists() { "found" } else { "not found" });
+    let project_file = Config::project_file_path();
+    if project_file.exists() {
+        println!("  {:<14} {}", "project_file", project_file.display());
+    }
+    println!("{}", "Precedence: command line flags > environment (.env) > config file > defaults".dimmed());
+}
+
+/// Messages shown when a conversation is loaded; older ones are only counted.
+const HISTORY_SHOWN: usize = 10;
+
+/// Prints the latest messages of a loaded conversation, so it's clear where it left off.
+fn print_history(conversation: &Conversation, config: &Config) {
+    let exchanged: Vec<&Message> = conversation.messages
+        .iter()
+        .filter(|m| matches!(m.role, Role::User | Role::Assistant) && !(m.is_tool_request() && m.content.is_empty()))
+        .collect();
+    let hidden = exchanged.len().saturating_sub(HISTORY_SHOWN);
+    if hidden > 0 {
+        println!("{}", format!("({} earlier messages not shown)", hidden).dimmed());
+    }
+    
+    for message in &exchanged[hidden..] {
+        let content = sanitize_terminal_output(&message.content);
+        let content = if config.max_display_chars > 0 {
+            elide_middle(&content, config.max_display_chars)
+        } else {
+            content
+        };
+        match message.role {
+            Role::User => {
+                let time = if config.show_timestamps { format!("[{}] ", short_time(message.created_at)) } else { String::new() };
+                println!("{}{} {}", time.dimmed(), "You:".bold(), content);
+            },
+            _ => println!("{} {}", ai_label(message.created_at, config), styled_response(content, config)),
+        }
+    }
+}
+
+/// A time as the local wall clock shows it: `14:32` today, `Oct 3 14:32` on other days.
+fn short_time(time: DateTime<Utc>) -> String {
+    let local = time.with_timezone(&Local);
+    if local.date_naive() == Local::now().date_naive() {
+        local.format("%H:%M").to_string()
+    } else {
+        local.format("%b %-d %H:%M").to_string()
+    }
+}
+
+/// The `AI:` label in front of a response, with the time it was written when timestamps are on.
+fn ai_label(time: DateTime<Utc>, config: &Config) -> String {
+    let label = "AI:".green().bold();
+    if config.show_timestamps {
+        format!("{} {}", format!("[{}]", short_time(time)).dimmed(), label)
+    } else {
+        label.to_string()
+    }
+}
+
+fn print_response(response: &Message, config: &Config) {
+    // Model output is untrusted: never let it drive the terminal
+    let content = sanitize_terminal_output(&response.content);
+    let content = if config.max_display_chars > 0 {
+        elide_middle(&content, config.max_display_chars)
+    } else {
+        content
+    };
+    println!("{} {}", ai_label(response.created_at, config), styled_response(content, config));
+    
+    if config.show_timings {
+        if let Some(stats) = &response.stream_stats {
+            print_stream_stats(stats);
+        }
+    }
+}
+
+/// Whether responses are rendered as Markdown: only on a terminal, so redirected output stays plain.
+fn renders_markdown(config: &Config) -> bool {
+    config.render_markdown && io::stdout().is_terminal()
+}
+
+/// Renders already sanitized response text as Markdown when that is enabled.
+fn styled_response(content: String, config: &Config) -> String {
+    if renders_markdown(config) {
+        MarkdownRenderer::render(&content)
+    } else {
+        content
+    }
+}
+
+/// Shows the tokens reported by the API for the current conversation and the whole
+/// session, with an estimated cost when prices are configured, and how much of the
+/// context window the conversation fills.
+fn print_usage(conversation: &Conversation, session: &TokenUsage, config: &Config) {
+    let priced = config.prompt_price_per_1k > 0.0 || config.completion_price_per_1k > 0.0;
+    
+    println!("{}", "Token usage:".bold());
+    for (label, usage) in [("conversation", &conversation.usage), ("session", session)] {
+        let cost = if priced {
+            format!("  ~{:.4}", usage.cost(config.prompt_price_per_1k, config.completion_price_per_1k))
+        } else {
+            String::new()
+        };
+        println!(
+            "  {:<13} {} prompt + {} completion = {} tokens{}",
+            label, usage.prompt_tokens, usage.completion_tokens, usage.total(), cost
+        );
+    }
+    println!(
+        "  {:<13} {} of {} tokens in the context window",
+        "context",
+        conversation.total_tokens_estimate(&config.openai_api_model),
+        config.context_window_tokens
+    );
+    if !priced {
+        println!("{}", "Set PROMPT_PRICE_PER_1K and COMPLETION_PRICE_PER_1K to estimate costs.".dimmed());
+    }
+}
+
+/// Shows the generation parameters in effect for `conversation` and where each comes from.
+fn print_params(conversation: &Conversation, config: &Config) {
+    let params = conversation.params.clone().unwrap_or_default();
+    let show = |value: Option<String>, fallback: Option<String>| match (value, fallback) {
+        (Some(value), _) => format!("{} (this conversation)", value),
+        (None, Some(fallback)) => format!("{} (config)", fallback),
+        (None, None) => "provider default".to_string(),
+    };
+    
+    println!("{}", "Generation parameters:".bold());
+    println!("  {:<12} {}", "temperature", show(params.temperature.map(|t| t.to_string()), config.temperature.map(|t| t.to_string())));
+    println!("  {:<12} {}", "top_p", show(params.top_p.map(|p| p.to_string()), config.top_p.map(|p| p.to_string())));
+    println!("  {:<12} {}", "max_tokens", show(params.max_tokens.map(|m| m.to_string()), config.max_tokens.map(|m| m.to_string())));
+}
+
+/// Prints two labelled answers in columns that share the terminal width (`$COLUMNS`, default 100).
+fn print_side_by_side(left: (&str, &str), right: (&str, &str)) {
+    let width = env::var("COLUMNS").ok().and_then(|v| v.parse::<usize>().ok()).unwrap_or(100);
+    let column = (width.saturating_sub(3) / 2).max(20);
+    
+    let left_lines = wrap_text(&sanitize_terminal_output(left.1), column);
+    let right_lines = wrap_text(&sanitize_terminal_output(right.1), column);
+    
+    println!("{} │ {}", format!("{:<column$}", format!("[1] {}", left.0)).bold(), format!("[2] {}", right.0).bold());
+    println!("{}┼{}", "─".repeat(column + 1), "─".repeat(column + 1));
+    for pair in left_lines.iter().map(String::as_str).zip_longest(right_lines.iter().map(String::as_str)) {
+        let (l, r) = pair.or("", "");
+        let padding = column.saturating_sub(l.chars().count());
+        println!("{}{} │ {}", l, " ".repeat(padding), r);
+    }
+}
+
+/// Builds the clearly delimited system block that carries `referenced` into another
+/// conversation, either verbatim or summarized by the model.
+async fn reference_block(agent: &OpenAIAgent, referenced: &Conversation, mode: ReferenceMode) -> Result<String> {
+    let body = match mode {
+        ReferenceMode::Full => referenced.transcript(),
+        ReferenceMode::Summary => agent.summarize(&referenced.transcript()).await?,
+    };
+    
+    Ok(format!(
+        "[Reference: earlier conversation \"{}\"]\n{}\n[End of reference]",
+        referenced.title, body
+    ))
+}
+
+/// Appends `response` to the tee file, separated from earlier responses by a timestamped rule.
+fn tee_response(path: &Path, response: &Message) -> Result<()> {
+    let mut file = open_tee(path)?;
+    writeln!(file, "{}", response.content)?;
+    Ok(())
+}
+
+/// Opens the tee file for appending and writes the timestamped separator for a new response.
+fn open_tee(path: &Path) -> Result<fs::File> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "\n--- {} ---", Local::now().format("%Y-%m-%d %H:%M:%S"))?;
+    Ok(file)
+}
+
+/// Prints a response as it streams in, copying each piece to the tee file when one
+/// is active, and returns the assembled message. Post-processors only affect the
+/// saved message, since the text is already on screen by the time they run.
+async fn stream_response(
+    agent: &OpenAIAgent,
+    conversation: &Conversation,
+    config: &Config,
+    tee_path: Option<&Path>,
+) -> Result<Message> {
+    let started = Instant::now();
+    let mut tee = tee_path.and_then(|path| match open_tee(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            println!("{} Failed to write to {}: {}", "Warning:".yellow().bold(), path.display(), e);
+            None
+        },
+    });
+    
+    let mut stream = pin!(agent.chat_stream(conversation));
+    let mut markdown = renders_markdown(config).then(MarkdownRenderer::default);
+    let mut pending_line = String::new();
+    let mut content = String::new();
+    let mut chunks = 0;
+    let mut first_token = None;
+    while let Some(piece) = stream.next().await {
+        let piece = match piece {
+            Ok(piece) => piece,
+            Err(e) => {
+                // Keep what was already printed and start the error on a fresh line
+                if first_token.is_some() {
+                    println!("{}", pending_line);
+                }
+                return Err(e);
+            },
+        };
+        if first_token.is_none() {
+            first_token = Some(started.elapsed());
+            clear_thinking_indicator()?;
+            print!("{} ", ai_label(Utc::now(), config));
+            CURRENT_LINE.store(LINE_TEXT, Ordering::Relaxed);
+        }
+        
+        // Model output is untrusted: never let it drive the terminal
+        let text = sanitize_terminal_output(&piece);
+        match &mut markdown {
+            // Markdown is rendered a line at a time, once each line is complete
+            Some(renderer) => {
+                pending_line.push_str(&text);
+                while let Some(end) = pending_line.find('\n') {
+                    println!("{}", renderer.render_line(&pending_line[..end]));
+                    pending_line.drain(..=end);
+                    CURRENT_LINE.store(LINE_EMPTY, Ordering::Relaxed);
+                }
+            },
+            None => {
+                print!("{}", text);
+                if !text.is_empty() {
+                    CURRENT_LINE.store(if text.ends_with('\n') { LINE_EMPTY } else { LINE_TEXT }, Ordering::Relaxed);
+                }
+            },
+        }
+        io::stdout().flush()?;
+        if let Some(file) = &mut tee {
+            if let Err(e) = file.write_all(piece.as_bytes()) {
+                println!("\n{} Failed to write to the tee file: {}", "Warning:".yellow().bold(), e);
+                tee = None;
+            }
+        }
+        
+        content.push_str(&piece);
+        chunks += 1;
+    }
+    let total = started.elapsed();
+    
+    if first_token.is_none() {
+        clear_thinking_indicator()?;
+        print!("{} ", ai_label(Utc::now(), config));
+    }
+    if let Some(renderer) = &mut markdown {
+        print!("{}", renderer.render_line(&pending_line));
+    }
+    println!();
+    CURRENT_LINE.store(LINE_EMPTY, Ordering::Relaxed);
+    if let Some(file) = &mut tee {
+        let _ = writeln!(file);
+    }
+    
+    let response = agent.streamed_message(content, chunks, first_token.unwrap_or(total), total);
+    if config.show_timings {
+        if let Some(stats) = &response.stream_stats {
+            print_stream_stats(stats);
+        }
+    }
+    Ok(response)
+}
+
+/// What the terminal's current line holds, so tool call notices can start on a line of their own.
+static CURRENT_LINE: AtomicU8 = AtomicU8::new(LINE_EMPTY);
+const LINE_EMPTY: u8 = 0;
+const LINE_INDICATOR: u8 = 1;
+const LINE_TEXT: u8 = 2;
+
+/// Shows "AI: <phrase>" while waiting for the response.
+fn show_thinking_indicator(phrase: &str) -> Result<()> {
+    print!("AI: {}", phrase);
+    io::stdout().flush()?;
+    CURRENT_LINE.store(LINE_INDICATOR, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Erases the thinking indicator line so the response doesn't stack after it.
+fn clear_thinking_indicator() -> Result<()> {
+    print!("\r\x1b[2K");
+    io::stdout().flush()?;
+    CURRENT_LINE.store(LINE_EMPTY, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Longest argument value shown in a tool call notice.
+const TOOL_ARGUMENT_CHARS: usize = 60;
+
+/// Prints a dim `→ calling name(arg="value")` line before a tool runs, replacing the
+/// thinking indicator or ending any partly streamed line first.
+fn print_tool_call(name: &str, arguments: &Value) {
+    match CURRENT_LINE.swap(LINE_EMPTY, Ordering::Relaxed) {
+        LINE_INDICATOR => print!("\r\x1b[2K"),
+        LINE_TEXT => println!(),
+        _ => {},
+    }
+    
+    let arguments = match arguments {
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(key, value)| {
+                let value = value.to_string();
+                if value.chars().count() > TOOL_ARGUMENT_CHARS {
+                    format!("{}={}…", key, truncate_chars(&value, TOOL_ARGUMENT_CHARS))
+                } else {
+                    format!("{}={}", key, value)
+                }
+            })
+            .join(", "),
+        other => other.to_string(),
+    };
+    let notice = sanitize_terminal_output(&format!("→ calling {}({})", name, arguments));
+    println!("{}", notice.dimmed());
+    let _ = io::stdout().flush();
+}
+
+/// Reads a local image for attaching to a message, or refers to one by URL.
+fn image_part(source: &str) -> Result<ContentPart> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        return Ok(ContentPart::ImageUrl { url: source.to_string() });
+    }
+    
+    let path = Path::new(source);
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_lowercase();
+    let mime = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => anyhow::bail!("unsupported image type (expected .png, .jpg, .gif or .webp)"),
+    };
+    let bytes = fs::read(path)?;
+    Ok(ContentPart::ImageBase64 {
+        data: BASE64_STANDARD.encode(bytes),
+        mime: mime.to_string(),
+    })
+}
+
+/// Opens `$EDITOR` (or `$VISUAL`, falling back to `vi`) on a temporary file and returns
+/// its contents. Returns `None` when the file is left empty or the editor fails.
+fn compose_in_editor() -> Result<Option<String>> {
+    let editor = env::var("EDITOR")
+        .or_else(|_| env::var("VISUAL"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let path = env::temp_dir().join(format!("ai-agent-prompt-{}.md", Uuid::new_v4()));
+    fs::write(&path, "")?;
+    
+    // The editor setting may carry arguments, e.g. "code --wait"
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let status = Command::new(program).args(parts).arg(&path).status();
+    let contents = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+    
+    if !status?.success() {
+        return Ok(None);
+    }
+    
+    let text = contents?.trim().to_string();
+    Ok(if text.is_empty() { None } else { Some(text) })
+}
+
+fn print_stream_stats(stats: &StreamStats) {
+    let line = format!(
+        "[{} chunk{} · first token {} ms · ~{:.1} tokens/s]",
+        stats.chunks,
+        if stats.chunks == 1 { "" } else { "s" },
+        stats.time_to_first_token_ms,
+        stats.tokens_per_second
+    );
+    println!("{}", line.dimmed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_flag_keeps_spaces_in_the_remaining_path() {
+        assert_eq!(take_flag("my backups/all.zip", "--include-system"), (false, "my backups/all.zip"));
+        assert_eq!(take_flag("--include-system my backups/all.zip", "--include-system"), (true, "my backups/all.zip"));
+        assert_eq!(take_flag("my backups/all.zip --include-system", "--include-system"), (true, "my backups/all.zip"));
+        assert_eq!(take_flag("--include-system", "--include-system"), (true, ""));
+        assert_eq!(take_flag("--include-systemic.zip", "--include-system"), (false, "--include-systemic.zip"));
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{bail, Result};
+use colored::*;
+use rustyline::DefaultEditor;
+use toml::{Table, Value};
+
+use crate::config::{normalize_base_url, Config};
+use crate::util::truncate_chars;
+
+const EDITABLE_SETTINGS: &[&str] = &["model", "base_url", "temperature", "system_prompt", "history_path"];
+
+/// Shows the effective settings and lets the user change them one at a time.
+/// Changes are applied to `config` and persisted to the config file; returns the
+/// names of the settings that changed.
+pub fn edit_settings(rl: &mut DefaultEditor, config: &mut Config) -> Result<Vec<String>> {
+    print_settings(config);
+    
+    let mut changes = Table::new();
+    let mut changed = Vec::new();
+    
+    loop {
+        let name = rl.readline("Setting to change (Enter to finish): ")?;
+        let name = name.trim();
+        if name.is_empty() {
+            break;
+        }
+        if !EDITABLE_SETTINGS.contains(&name) {
+            println!("Unknown setting '{}'. Choose one of: {}", name, EDITABLE_SETTINGS.join(", "));
+            continue;
+        }
+        
+        let value = rl.readline(&format!("New value for {}: ", name))?;
+        match apply_setting(config, name, value.trim()) {
+            Ok((key, value)) => {
+                changes.insert(key.to_string(), value);
+                changed.push(name.to_string());
+            },
+            Err(e) => {
+                println!("Invalid value for {}: {}", name, e);
+            }
+        }
+    }
+    
+    if !changes.is_empty() {
+        let keys: Vec<String> = changes.keys().cloned().collect();
+        let path = Config::persist_settings(changes)?;
+        
+        let mut shadowed = 0;
+        for key in &keys {
+            let overrides = Config::setting_overrides(key)?;
+            if !overrides.is_empty() {
+                shadowed += 1;
+                println!("{}", format!(
+                    "Warning: saved {} to {}, but {} overrides it on the next start",
+                    key, path.display(), overrides.join(" and ")
+                ).yellow());
+            }
+        }
+        if shadowed < keys.len() {
+            println!("Saved settings to {}", path.display());
+        }
+        
+        if changed.iter().any(|name| name == "history_path") {
+            println!("{}", "history_path takes effect after restarting ai-agent".yellow());
+        }
+    }
+    
+    Ok(changed)
+}
+
+fn print_settings(config: &Config) {
+    let temperature = config.temperature
+        .map(|t| t.to_string())
+        .unwrap_or_else(|| "provider default".to_string());
+    let system_prompt = if config.system_prompt.chars().count() > 60 {
+        format!("{}…", truncate_chars(&config.system_prompt, 60))
+    } else {
+        config.system_prompt.clone()
+    };
+    
+    println!("{}", "Current settings:".bold());
+    println!("  {:<14} {}", "model", config.openai_api_model);
+    println!("  {:<14} {}", "base_url", config.openai_api_base_url);
+    println!("  {:<14} {}", "temperature", temperature);
+    println!("  {:<14} {}", "system_prompt", system_prompt);
+    println!("  {:<14} {} {}", "history_path", config.history_path.display(), "(requires restart)".dimmed());
+    println!("  {:<14} {} {}", "api_key", config.masked_api_key(), "(read-only)".dimmed());
+}
+
+/// Applies a single setting to the session config and returns the config file key and value to persist.
+fn apply_setting(config: &mut Config, name: &str, value: &str) -> Result<(&'static str, Value)> {
+    if value.is_empty() {
+        bail!("value cannot be empty");
+    }
+    
+    match name {
+        "model" => {
+            config.openai_api_model = value.to_string();
+            Ok(("openai_api_model", Value::String(value.to_string())))
+        },
+        "base_url" => {
+            let url = normalize_base_url(value);
+            config.openai_api_base_url = url.clone();
+            Ok(("openai_api_base_url", Value::String(url)))
+        },
+        "temperature" => {
+            let temperature: f64 = value.parse()?;
+            if !(0.0..=2.0).contains(&temperature) {
+                bail!("temperature must be between 0 and 2");
+            }
+            config.temperature = Some(temperature as f32);
+            Ok(("temperature", Value::Float(temperature)))
+        },
+        "system_prompt" => {
+            config.system_prompt = value.to_string();
+            Ok(("system_prompt", Value::String(value.to_string())))
+        },
+        // Only persisted: the current session keeps saving to the directory it started with
+        "history_path" => Ok(("history_path", Value::String(value.to_string()))),
+        _ => bail!("unknown setting"),
+    }
+}
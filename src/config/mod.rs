@@ -1,9 +1,61 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
-use anyhow::Result;
+use std::fmt;
+use std::io;
+use anyhow::{Context, Result};
 use dotenv::dotenv;
 use dirs::home_dir;
-use std::path::PathBuf;
+use fs_err as fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::agent::{write_atomically, PostProcessor};
+
+mod provider;
+
+pub use provider::{ApiFormat, AuthScheme, Provider, ProviderKind};
+
+const DEFAULT_SYSTEM_PROMPT: &str = "You are an AI assistant with access to Context7 libraries. You can help users \
+    by providing documentation and assistance related to various programming libraries. \
+    To use a library, you'll first need to resolve its ID and then fetch its documentation.";
+
+/// Replaces the default prompt when the Context7 tools are unavailable, so the
+/// model doesn't promise documentation it can't fetch.
+const NO_TOOLS_SYSTEM_PROMPT: &str = "You are an AI assistant that helps users with programming questions. \
+    You have no access to library documentation in this session, so answer from your own knowledge \
+    and say so when you are unsure whether it is current.";
+
+/// Per-project config file looked up in the current directory.
+const PROJECT_CONFIG_FILE: &str = "ai-agent.toml";
+
+/// Environment variables that override settings saved by `!config`.
+const SETTING_ENV_VARS: &[(&str, &str)] = &[
+    ("openai_api_model", "OPENAI_API_MODEL"),
+    ("openai_api_base_url", "OPENAI_API_BASE_URL"),
+    ("temperature", "OPENAI_TEMPERATURE"),
+    ("system_prompt", "SYSTEM_PROMPT"),
+    ("history_path", "HISTORY_PATH"),
+];
+
+/// Keyring service under which API keys are stored, one entry per provider.
+const KEYRING_SERVICE: &str = "ai-agent";
+
+/// Values accepted by reasoning models for `reasoning_effort`.
+pub const REASONING_EFFORTS: &[&str] = &["low", "medium", "high"];
+
+/// Known provider hosts that serve their API under a `/v1` path.
+const VERSIONED_API_HOSTS: &[&str] = &[
+    "api.openai.com",
+    "api.anthropic.com",
+    "api.groq.com/openai",
+    "api.mistral.ai",
+    "api.together.xyz",
+    "openrouter.ai/api",
+    "localhost:11434",
+    "127.0.0.1:11434",
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct McpConfig {
@@ -11,19 +63,177 @@ pub struct McpConfig {
     pub args: Vec<String>,
 }
 
+/// MCP servers to start, keyed by name (`[mcp_servers.<name>]` in config.toml).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct McpServers {
-    pub context7: McpConfig,
+    #[serde(flatten)]
+    pub servers: HashMap<String, McpConfig>,
+}
+
+/// HTTP protocol selection for outgoing requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    #[default]
+    Auto,
+    Http1,
+    Http2,
+}
+
+/// How `!reference` brings another conversation into the current one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReferenceMode {
+    /// Every message, verbatim
+    #[default]
+    Full,
+    /// A summary written by the model
+    Summary,
+}
+
+impl ReferenceMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "full" => Some(Self::Full),
+            "summary" => Some(Self::Summary),
+            _ => None,
+        }
+    }
+}
+
+/// How untitled conversations are named when they are saved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TitleStrategy {
+    /// The first user message, shortened
+    #[default]
+    FirstMessage,
+    /// A title written by the model
+    Llm,
+    /// The date and time the conversation started
+    Timestamp,
+}
+
+/// An API key that never shows up in `Debug` output, so logging a config can't leak it.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(key.into())
+    }
+
+    /// The key itself, for the auth header.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
-    pub openai_api_key: String,
+    pub openai_api_key: ApiKey,
     pub openai_api_base_url: String,
     pub openai_api_model: String,
+    /// Explicit provider; detected from the base URL when unset
+    pub provider: Option<ProviderKind>,
+    /// Fraction of requests the mock provider fails, for exercising error handling
+    pub mock_error_rate: f32,
+    /// Include prompts, responses and tool payloads in debug logs
+    pub log_prompts: bool,
+    pub temperature: Option<f32>,
+    /// Nucleus sampling cutoff; the provider default applies when unset
+    pub top_p: Option<f32>,
+    /// Upper bound on tokens generated per response; the provider default applies when unset
+    pub max_tokens: Option<u32>,
+    /// Reasoning effort for o-series models; ignored for other models
+    pub reasoning_effort: Option<String>,
+    pub system_prompt: String,
+    /// Additional system instructions layered after the base system prompt
+    pub extra_system_prompts: Vec<String>,
     pub agent_name: String,
     pub history_path: PathBuf,
+    /// Named profile whose conversations are kept apart in `history_path/<profile>`
+    pub profile: Option<String>,
     pub mcp_servers: McpServers,
+    pub mcp_enabled: bool,
+    /// Seconds to wait for the MCP server to answer a call
+    pub mcp_timeout_secs: u64,
+    /// Seconds an MCP server may take to start and answer the handshake
+    pub mcp_startup_timeout_secs: u64,
+    pub show_timings: bool,
+    /// Text shown while waiting for a response; separate phrases with `|` to cycle through them
+    pub thinking_message: String,
+    /// Maximum characters of tool output kept in the conversation
+    pub max_tool_output_chars: usize,
+    /// Price per 1,000 prompt tokens, used to estimate costs in `!tokens` (0 = unknown)
+    pub prompt_price_per_1k: f64,
+    /// Price per 1,000 completion tokens
+    pub completion_price_per_1k: f64,
+    /// Rounds of tool calls per answer before the agent stops and answers with what it has
+    pub max_tool_iterations: usize,
+    /// Template for a system note generated on every request; supports `{date}`, `{time}`, `{cwd}` and `{os}`
+    pub context_template: Option<String>,
+    /// Seconds to wait for the API to respond; for streamed responses, the longest gap between chunks
+    pub request_timeout_secs: u64,
+    /// Times a request that failed with 429 or a 5xx status is sent again
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each further one, unless the API sends `Retry-After`
+    pub retry_backoff_ms: u64,
+    /// Force HTTP/1.1 or HTTP/2 for proxies that misbehave with protocol negotiation
+    pub http_version: HttpVersion,
+    /// Merge and pad messages so turns alternate starting with `user`, for strict backends
+    pub normalize_roles: bool,
+    /// Responses longer than this are shown shortened in the middle (0 = no limit)
+    pub max_display_chars: usize,
+    /// How untitled conversations are named when saved
+    pub title_strategy: TitleStrategy,
+    /// Default for `!reference` when no mode is given
+    pub reference_mode: ReferenceMode,
+    /// Ask before `!clear` and `!new` discard the current conversation
+    pub confirm_discard: bool,
+    /// Print chat responses as they are generated instead of all at once
+    pub stream: bool,
+    /// Render Markdown in responses (headings, lists, emphasis, highlighted code) when printing to a terminal
+    pub render_markdown: bool,
+    /// Print requests instead of sending them, answering with a placeholder
+    pub dry_run: bool,
+    /// Prefix messages in the chat with the local time they were written
+    pub show_timestamps: bool,
+    /// Model used to embed messages for `!find`
+    pub embedding_model: String,
+    /// Read the API key from the OS keyring before falling back to the environment
+    pub use_keyring: bool,
+    /// Refuse to start a chat while another session uses the same history directory
+    pub single_instance: bool,
+    /// Context window of the model in tokens, used to warn before conversations outgrow it
+    pub context_window_tokens: usize,
+    /// Estimated tokens of history sent per request; older messages beyond it are left
+    /// out (0 = the context window minus room for the response)
+    pub max_context_tokens: usize,
+    /// Start a new conversation automatically once this many messages are reached (0 = no limit)
+    pub max_messages_per_conversation: usize,
+    /// Seed the conversation started by a rollover with a summary of the one that ended
+    pub rollover_summary: bool,
+    /// Save the conversation after every this many exchanges (0 = after every exchange)
+    pub autosave_every: usize,
+    /// Summarize the oldest messages before sending once a conversation exceeds this many tokens (0 = never)
+    pub summarize_after_tokens: usize,
+    /// Number of newest messages kept verbatim when older ones are summarized
+    pub summary_keep_recent: usize,
+    /// Transforms applied in order to assistant responses before display and save
+    pub post_processors: Vec<PostProcessor>,
 }
 
 impl Default for Config {
@@ -33,32 +243,91 @@ impl Default for Config {
         history_path.push("history");
 
         Self {
-            openai_api_key: String::new(),
+            openai_api_key: ApiKey::default(),
             openai_api_base_url: "https://api.openai.com/v1".to_string(),
-            openai_api_model: "gpt-4-turbo".to_string(),
+            openai_api_model: ProviderKind::OpenAI.descriptor().default_model.to_string(),
+            provider: None,
+            mock_error_rate: 0.0,
+            log_prompts: true,
+            temperature: Some(0.7),
+            top_p: None,
+            max_tokens: None,
+            reasoning_effort: None,
+            system_prompt: DEFAULT_SYSTEM_PROMPT.to_string(),
+            extra_system_prompts: Vec::new(),
             agent_name: "ai-assistant".to_string(),
             history_path,
+            profile: None,
             mcp_servers: McpServers {
-                context7: McpConfig {
-                    command: "npx".to_string(),
-                    args: vec!["-y".to_string(), "@upstash/context7-mcp@latest".to_string()],
-                },
+                servers: HashMap::from([(
+                    "context7".to_string(),
+                    McpConfig {
+                        command: "npx".to_string(),
+                        args: vec!["-y".to_string(), "@upstash/context7-mcp@latest".to_string()],
+                    },
+                )]),
             },
+            mcp_enabled: true,
+            mcp_timeout_secs: 30,
+            mcp_startup_timeout_secs: 10,
+            show_timings: false,
+            thinking_message: "Thinking".to_string(),
+            max_tool_output_chars: 20_000,
+            max_tool_iterations: 5,
+            prompt_price_per_1k: 0.0,
+            completion_price_per_1k: 0.0,
+            context_template: None,
+            request_timeout_secs: 60,
+            max_retries: 3,
+            retry_backoff_ms: 500,
+            http_version: HttpVersion::Auto,
+            normalize_roles: false,
+            max_display_chars: 0,
+            title_strategy: TitleStrategy::FirstMessage,
+            reference_mode: ReferenceMode::Full,
+            confirm_discard: true,
+            stream: true,
+            render_markdown: true,
+            show_timestamps: false,
+            dry_run: false,
+            embedding_model: "text-embedding-3-small".to_string(),
+            use_keyring: true,
+            single_instance: true,
+            context_window_tokens: 128_000,
+            max_context_tokens: 0,
+            max_messages_per_conversation: 0,
+            rollover_summary: false,
+            autosave_every: 0,
+            summarize_after_tokens: 0,
+            summary_keep_recent: 6,
+            post_processors: Vec::new(),
         }
     }
 }
 
 impl Config {
+    /// Loads the configuration, each source overriding the ones before it:
+    ///
+    /// 1. built-in defaults
+    /// 2. `~/.ai-agent/config.toml`
+    /// 3. `./ai-agent.toml` in the current directory, for per-project settings
+    /// 4. environment variables, including those from a `.env` file
+    ///
+    /// Command line flags are applied on top by the caller. An API key must come
+    /// from one of these sources or the OS keyring; see [`Config::require_api_key`].
     pub fn load() -> Result<Self> {
         // Load environment variables from .env file
         dotenv().ok();
         
-        // Start with default configuration
-        let mut config = Config::default();
+        // Start with default configuration, overlaid by the config file if it exists
+        let (mut config, mut explicit_model) = match Config::load_file()? {
+            Some((config, explicit_model)) => (config, explicit_model),
+            None => (Config::default(), false),
+        };
         
         // Override with environment variables if they exist
         if let Ok(api_key) = env::var("OPENAI_API_KEY") {
-            config.openai_api_key = api_key;
+            config.openai_api_key = ApiKey::new(api_key);
         }
         
         if let Ok(api_base) = env::var("OPENAI_API_BASE_URL") {
@@ -67,6 +336,43 @@ impl Config {
         
         if let Ok(api_model) = env::var("OPENAI_API_MODEL") {
             config.openai_api_model = api_model;
+            explicit_model = true;
+        }
+        
+        // An empty value falls back to the provider default
+        if let Ok(temperature) = env::var("OPENAI_TEMPERATURE") {
+            config.temperature = parse_optional(&temperature)
+                .filter(|t: &Option<f32>| t.is_none_or(|t| (0.0..=2.0).contains(&t)))
+                .ok_or_else(|| anyhow::anyhow!("Invalid OPENAI_TEMPERATURE '{}': expected a number between 0 and 2", temperature))?;
+        }
+        if let Ok(top_p) = env::var("OPENAI_TOP_P") {
+            config.top_p = parse_optional(&top_p)
+                .filter(|p: &Option<f32>| p.is_none_or(|p| (0.0..=1.0).contains(&p)))
+                .ok_or_else(|| anyhow::anyhow!("Invalid OPENAI_TOP_P '{}': expected a number between 0 and 1", top_p))?;
+        }
+        if let Ok(max_tokens) = env::var("OPENAI_MAX_TOKENS") {
+            config.max_tokens = parse_optional(&max_tokens)
+                .filter(|m: &Option<u32>| *m != Some(0))
+                .ok_or_else(|| anyhow::anyhow!("Invalid OPENAI_MAX_TOKENS '{}': expected a positive whole number", max_tokens))?;
+        }
+        
+        if let Ok(effort) = env::var("OPENAI_REASONING_EFFORT") {
+            config.reasoning_effort = Some(effort.trim().to_lowercase());
+        }
+        if let Some(effort) = &config.reasoning_effort {
+            if !REASONING_EFFORTS.contains(&effort.as_str()) {
+                anyhow::bail!("Invalid reasoning effort '{}': expected one of {}", effort, REASONING_EFFORTS.join(", "));
+            }
+        }
+        
+        if let Ok(prompt) = env::var("SYSTEM_PROMPT") {
+            if !prompt.trim().is_empty() {
+                config.system_prompt = prompt;
+            }
+        }
+        
+        if let Ok(extra) = env::var("EXTRA_SYSTEM_PROMPT") {
+            config.extra_system_prompts = vec![extra];
         }
         
         if let Ok(agent_name) = env::var("AGENT_NAME") {
@@ -74,15 +380,506 @@ impl Config {
         }
         
         if let Ok(history_path) = env::var("HISTORY_PATH") {
-            let path = history_path.replace("~", home_dir().unwrap_or_default().to_str().unwrap_or(""));
-            config.history_path = PathBuf::from(path);
+            config.history_path = PathBuf::from(history_path);
+        }
+        config.history_path = expand_home(&config.history_path);
+        
+        if let Ok(profile) = env::var("AGENT_PROFILE") {
+            config.profile = Some(profile).filter(|p| !p.trim().is_empty());
+        }
+        
+        if let Some(mcp_enabled) = env_flag("MCP_ENABLED") {
+            config.mcp_enabled = mcp_enabled;
+        }
+        
+        if let Some(timeout) = env::var("MCP_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()) {
+            config.mcp_timeout_secs = timeout;
+        }
+        if let Some(timeout) = env::var("MCP_STARTUP_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()) {
+            config.mcp_startup_timeout_secs = timeout;
+        }
+        
+        if let Ok(thinking_message) = env::var("THINKING_MESSAGE") {
+            config.thinking_message = thinking_message;
+        }
+        
+        if let Some(max_chars) = env::var("MAX_TOOL_OUTPUT_CHARS").ok().and_then(|v| v.parse().ok()) {
+            config.max_tool_output_chars = max_chars;
+        }
+        
+        if let Some(max_iterations) = env::var("MAX_TOOL_ITERATIONS").ok().and_then(|v| v.parse().ok()) {
+            config.max_tool_iterations = max_iterations;
+        }
+        
+        if let Some(timeout) = env::var("REQUEST_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()) {
+            config.request_timeout_secs = timeout;
+        }
+        if let Some(retries) = env::var("MAX_RETRIES").ok().and_then(|v| v.parse().ok()) {
+            config.max_retries = retries;
+        }
+        if let Some(backoff) = env::var("RETRY_BACKOFF_MS").ok().and_then(|v| v.parse().ok()) {
+            config.retry_backoff_ms = backoff;
+        }
+        
+        if let Some(price) = env::var("PROMPT_PRICE_PER_1K").ok().and_then(|v| v.parse().ok()) {
+            config.prompt_price_per_1k = price;
+        }
+        if let Some(price) = env::var("COMPLETION_PRICE_PER_1K").ok().and_then(|v| v.parse().ok()) {
+            config.completion_price_per_1k = price;
+        }
+        
+        if let Ok(template) = env::var("CONTEXT_TEMPLATE") {
+            config.context_template = Some(template).filter(|t| !t.trim().is_empty());
+        }
+        
+        if let Some(confirm_discard) = env_flag("CONFIRM_DISCARD") {
+            config.confirm_discard = confirm_discard;
+        }
+        
+        if let Some(stream) = env_flag("STREAM") {
+            config.stream = stream;
+        }
+        
+        if let Some(render_markdown) = env_flag("RENDER_MARKDOWN") {
+            config.render_markdown = render_markdown;
+        }
+        
+        if let Some(dry_run) = env_flag("DRY_RUN") {
+            config.dry_run = dry_run;
+        }
+        
+        if let Some(show_timestamps) = env_flag("SHOW_TIMESTAMPS") {
+            config.show_timestamps = show_timestamps;
+        }
+        
+        if let Ok(model) = env::var("EMBEDDING_MODEL") {
+            config.embedding_model = model;
+        }
+        
+        if let Some(use_keyring) = env_flag("USE_KEYRING") {
+            config.use_keyring = use_keyring;
+        }
+        
+        if let Some(normalize_roles) = env_flag("NORMALIZE_ROLES") {
+            config.normalize_roles = normalize_roles;
+        }
+        
+        if let Some(single_instance) = env_flag("SINGLE_INSTANCE") {
+            config.single_instance = single_instance;
+        }
+        
+        if let Some(tokens) = env::var("SUMMARIZE_AFTER_TOKENS").ok().and_then(|v| v.parse().ok()) {
+            config.summarize_after_tokens = tokens;
+        }
+        
+        if let Some(count) = env::var("SUMMARY_KEEP_RECENT").ok().and_then(|v| v.parse().ok()) {
+            config.summary_keep_recent = count;
+        }
+        
+        if let Some(chars) = env::var("MAX_DISPLAY_CHARS").ok().and_then(|v| v.parse().ok()) {
+            config.max_display_chars = chars;
+        }
+        
+        if let Some(tokens) = env::var("CONTEXT_WINDOW_TOKENS").ok().and_then(|v| v.parse().ok()) {
+            config.context_window_tokens = tokens;
+        }
+        
+        if let Some(tokens) = env::var("MAX_CONTEXT_TOKENS").ok().and_then(|v| v.parse().ok()) {
+            config.max_context_tokens = tokens;
+        }
+        
+        if let Some(max_messages) = env::var("MAX_MESSAGES_PER_CONVERSATION").ok().and_then(|v| v.parse().ok()) {
+            config.max_messages_per_conversation = max_messages;
+        }
+        
+        if let Some(rollover_summary) = env_flag("ROLLOVER_SUMMARY") {
+            config.rollover_summary = rollover_summary;
+        }
+        
+        if let Some(every) = env::var("AUTOSAVE_EVERY").ok().and_then(|v| v.parse().ok()) {
+            config.autosave_every = every;
+        }
+        
+        if let Ok(names) = env::var("POST_PROCESSORS") {
+            config.post_processors = names
+                .split(',')
+                .filter(|name| !name.trim().is_empty())
+                .map(|name| PostProcessor::from_name(name).ok_or_else(|| anyhow::anyhow!(
+                    "Unknown post-processor '{}': expected trim, strip_filler or normalize_whitespace", name.trim()
+                )))
+                .collect::<Result<_>>()?;
+        }
+        
+        if let Ok(provider) = env::var("PROVIDER") {
+            config.provider = Some(ProviderKind::from_name(&provider).ok_or_else(|| anyhow::anyhow!(
+                "Invalid PROVIDER '{}': expected openai, ollama, groq, anthropic or mock", provider
+            ))?);
+        }
+        
+        if let Some(rate) = env::var("MOCK_ERROR_RATE").ok().and_then(|v| v.parse().ok()) {
+            config.mock_error_rate = rate;
+        }
+        
+        if let Some(log_prompts) = env_flag("LOG_PROMPTS") {
+            config.log_prompts = log_prompts;
+        }
+        
+        if let Ok(strategy) = env::var("TITLE_STRATEGY") {
+            config.title_strategy = match strategy.trim().to_lowercase().as_str() {
+                "first_message" => TitleStrategy::FirstMessage,
+                "llm" => TitleStrategy::Llm,
+                "timestamp" => TitleStrategy::Timestamp,
+                other => anyhow::bail!("Invalid TITLE_STRATEGY '{}': expected first_message, llm or timestamp", other),
+            };
+        }
+        
+        if let Ok(mode) = env::var("REFERENCE_MODE") {
+            config.reference_mode = ReferenceMode::from_name(&mode).ok_or_else(|| anyhow::anyhow!(
+                "Invalid REFERENCE_MODE '{}': expected full or summary", mode
+            ))?;
+        }
+        
+        if let Ok(http_version) = env::var("HTTP_VERSION") {
+            config.http_version = match http_version.trim().to_lowercase().as_str() {
+                "auto" => HttpVersion::Auto,
+                "http1" => HttpVersion::Http1,
+                "http2" => HttpVersion::Http2,
+                other => anyhow::bail!("Invalid HTTP_VERSION '{}': expected auto, http1 or http2", other),
+            };
+        }
+        
+        let normalized = normalize_base_url(&config.openai_api_base_url);
+        if normalized != config.openai_api_base_url {
+            info!("Using API base URL {} (added missing /v1 suffix to {})", normalized, config.openai_api_base_url);
+            config.openai_api_base_url = normalized;
+        }
+        
+        // Without an explicit model, pick one the detected provider actually serves
+        if !explicit_model {
+            config.openai_api_model = config.default_model().to_string();
         }
         
-        // Validate required configuration
-        if config.openai_api_key.is_empty() {
-            anyhow::bail!("OPENAI_API_KEY environment variable is required");
+        // A key in the OS keyring wins over plaintext settings; without a usable
+        // keyring the environment and config file are used as before
+        if config.use_keyring {
+            if let Some(api_key) = config.keyring_api_key() {
+                config.openai_api_key = ApiKey::new(api_key);
+            }
         }
         
         Ok(config)
     }
-} 
\ No newline at end of file
+
+    /// Scopes the history to the configured profile, which also becomes the agent name,
+    /// so each profile has its own conversations and `conversations.json`. Call once,
+    /// after all overrides are applied.
+    pub fn apply_profile(&mut self) -> Result<()> {
+        let Some(profile) = &self.profile else {
+            return Ok(());
+        };
+        
+        // The name becomes a directory, so it must not point anywhere else
+        let valid = profile.chars().all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            && !profile.starts_with('.');
+        if !valid {
+            anyhow::bail!("Invalid profile name {:?}: use letters, digits, '-', '_' and '.'", profile);
+        }
+        
+        self.agent_name = profile.clone();
+        self.history_path = self.history_path.join(profile);
+        Ok(())
+    }
+
+    /// Fails unless an API key is available; providers without auth need none.
+    pub fn require_api_key(&self) -> Result<()> {
+        if self.openai_api_key.is_empty() && self.provider().auth_scheme != AuthScheme::None {
+            anyhow::bail!("OPENAI_API_KEY environment variable is required (or store the key with `ai-agent set-key`)");
+        }
+        Ok(())
+    }
+
+    /// Stores `key` in the OS keyring for the configured provider, so it no longer
+    /// has to sit in `.env` or the config file.
+    pub fn store_api_key(&self, key: &str) -> Result<()> {
+        keyring::Entry::new(KEYRING_SERVICE, self.provider().kind.name())
+            .and_then(|entry| entry.set_password(key))
+            .context("Could not store the API key in the OS keyring")
+    }
+
+    /// Reads the configured provider's API key from the OS keyring. A missing entry
+    /// or an unavailable keyring backend both yield `None`.
+    fn keyring_api_key(&self) -> Option<String> {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, self.provider().kind.name());
+        match entry.and_then(|entry| entry.get_password()) {
+            Ok(api_key) => Some(api_key).filter(|key| !key.is_empty()),
+            Err(keyring::Error::NoEntry) => None,
+            Err(e) => {
+                debug!("OS keyring unavailable, using OPENAI_API_KEY instead: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Location of the user's config file, `~/.ai-agent/config.toml`.
+    pub fn file_path() -> PathBuf {
+        let mut path = home_dir().unwrap_or_else(|| PathBuf::from("."));
+        path.push(".ai-agent");
+        path.push("config.toml");
+        path
+    }
+
+    /// Swaps the default system prompt for one that doesn't promise library
+    /// documentation, for sessions without the Context7 tools. A customized prompt
+    /// is kept. Returns whether the prompt changed.
+    pub fn use_prompt_without_docs_tools(&mut self) -> bool {
+        if self.system_prompt != DEFAULT_SYSTEM_PROMPT {
+            return false;
+        }
+        self.system_prompt = NO_TOOLS_SYSTEM_PROMPT.to_string();
+        true
+    }
+
+    /// Path of the per-project config file, which overrides the one in the home directory.
+    pub fn project_file_path() -> PathBuf {
+        PathBuf::from(PROJECT_CONFIG_FILE)
+    }
+
+    /// Loads the config files, also reporting whether they set the model explicitly.
+    fn load_file() -> Result<Option<(Self, bool)>> {
+        let mut merged: Option<toml::Table> = None;
+        for path in [Self::file_path(), Self::project_file_path()] {
+            let Some(table) = read_table(&path)? else {
+                continue;
+            };
+            match &mut merged {
+                Some(merged) => merge_tables(merged, table),
+                None => merged = Some(table),
+            }
+        }
+        
+        let Some(table) = merged else {
+            return Ok(None);
+        };
+        let explicit_model = table.contains_key("openai_api_model");
+        let config = toml::Value::Table(table).try_into()
+            .context("Invalid config file settings")?;
+        Ok(Some((config, explicit_model)))
+    }
+
+    /// Writes the given settings into the config file, keeping any other settings already there.
+    pub fn persist_settings(settings: toml::Table) -> Result<PathBuf> {
+        let path = Self::file_path();
+        let mut table = match fs::read_to_string(&path) {
+            Ok(contents) => contents.parse::<toml::Table>()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => toml::Table::new(),
+            Err(err) => return Err(err.into()),
+        };
+        table.extend(settings);
+        
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomically(&path, toml::to_string_pretty(&table)?.as_bytes())?;
+        
+        Ok(path)
+    }
+
+    /// Lists what would override `key` in the home config file on the next start:
+    /// the project config file and the matching environment variable, if set.
+    pub fn setting_overrides(key: &str) -> Result<Vec<String>> {
+        let mut overrides = Vec::new();
+        let project_path = Self::project_file_path();
+        if read_table(&project_path)?.is_some_and(|table| table.contains_key(key)) {
+            overrides.push(project_path.display().to_string());
+        }
+        
+        let env_var = SETTING_ENV_VARS.iter()
+            .find(|(setting, _)| *setting == key)
+            .map(|(_, var)| *var);
+        if let Some(var) = env_var {
+            if env::var(var).is_ok_and(|value| !value.trim().is_empty()) {
+                overrides.push(var.to_string());
+            }
+        }
+        
+        Ok(overrides)
+    }
+
+    /// The state file naming the last active conversation. It lives in the history
+    /// directory, so each profile keeps its own.
+    fn last_conversation_path(&self) -> PathBuf {
+        self.history_path.join("last_conversation")
+    }
+
+    /// The conversation most recently loaded or started, resumed by `ask --resume-last`.
+    pub fn last_conversation_id(&self) -> Option<String> {
+        fs::read_to_string(self.last_conversation_path())
+            .ok()
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+    }
+
+    /// Records `id` as the last active conversation. It is kept out of the config file,
+    /// which is only rewritten when the user changes a setting. Failures are only
+    /// logged, since losing this hint must never interrupt a session.
+    pub fn remember_last_conversation(&self, id: &str) {
+        // Dry runs leave no trace in the history
+        if self.dry_run {
+            return;
+        }
+        let path = self.last_conversation_path();
+        if let Err(e) = fs::create_dir_all(&self.history_path).and_then(|_| fs::write(&path, id)) {
+            warn!("Failed to record the last conversation in {}: {}", path.display(), e);
+        }
+    }
+
+    /// Estimated tokens of conversation history that may be sent with a request.
+    pub fn context_budget_tokens(&self) -> usize {
+        if self.max_context_tokens > 0 {
+            return self.max_context_tokens;
+        }
+        // Leave room for the answer, which shares the context window
+        let reserved = self.max_tokens.map(|tokens| tokens as usize).unwrap_or(4096);
+        self.context_window_tokens.saturating_sub(reserved)
+    }
+
+    /// Builds an HTTP client honoring the configured protocol version.
+    pub fn http_client(&self) -> reqwest::Client {
+        let builder = match self.http_version {
+            HttpVersion::Auto => reqwest::Client::builder(),
+            HttpVersion::Http1 => reqwest::Client::builder().http1_only(),
+            HttpVersion::Http2 => reqwest::Client::builder().http2_prior_knowledge(),
+        };
+        builder
+            .connect_timeout(self.request_timeout())
+            .build()
+            .expect("Failed to build HTTP client")
+    }
+
+    /// How long to wait for the API before giving up on a request.
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs.max(1))
+    }
+
+    /// Whether the configured model is an OpenAI o-series reasoning model.
+    pub fn is_reasoning_model(&self) -> bool {
+        let model = self.openai_api_model.rsplit('/').next().unwrap_or_default();
+        let mut chars = model.chars();
+        chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
+    }
+
+    /// The provider's descriptor: the configured one, or the one detected from the base URL.
+    pub fn provider(&self) -> &'static Provider {
+        self.provider
+            .unwrap_or_else(|| ProviderKind::detect(&self.openai_api_base_url))
+            .descriptor()
+    }
+
+    /// A sensible model for the selected provider.
+    pub fn default_model(&self) -> &'static str {
+        self.provider().default_model
+    }
+
+    /// The API key with all but its first and last few characters hidden.
+    pub fn masked_api_key(&self) -> String {
+        let chars: Vec<char> = self.openai_api_key.expose().chars().collect();
+        if chars.len() <= 8 {
+            return "*".repeat(chars.len());
+        }
+        
+        let head: String = chars[..3].iter().collect();
+        let tail: String = chars[chars.len() - 4..].iter().collect();
+        format!("{}…{}", head, tail)
+    }
+}
+
+/// Expands a leading `~` to the user's home directory.
+pub fn expand_home(path: &std::path::Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => home_dir().unwrap_or_default().join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Appends the `/v1` path segment to base URLs of known providers that lack it.
+/// Fully custom URLs are returned untouched apart from trailing slashes.
+pub fn normalize_base_url(url: &str) -> String {
+    let trimmed = url.trim().trim_end_matches('/');
+    let without_scheme = trimmed
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(trimmed);
+
+    if VERSIONED_API_HOSTS.contains(&without_scheme) {
+        format!("{}/v1", trimmed)
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Reads a TOML config file; a missing file is `None`.
+fn read_table(path: &Path) -> Result<Option<toml::Table>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents
+            .parse::<toml::Table>()
+            .map(Some)
+            .with_context(|| format!("Invalid config file {}", path.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Overlays `overrides` onto `base`. Nested tables are merged key by key, so a
+/// project file can add an MCP server without repeating the others.
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => merge_tables(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            },
+        }
+    }
+}
+
+/// Parses an optional setting: empty means unset, anything else must parse.
+/// Returns `None` when the value is invalid.
+fn parse_optional<T: std::str::FromStr>(value: &str) -> Option<Option<T>> {
+    let value = value.trim();
+    if value.is_empty() {
+        Some(None)
+    } else {
+        value.parse().ok().map(Some)
+    }
+}
+
+/// Reads a boolean environment variable, accepting `1/true/yes/on` and `0/false/no/off`.
+fn env_flag(name: &str) -> Option<bool> {
+    match env::var(name).ok()?.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_a_versioned_url() {
+        assert_eq!(normalize_base_url("https://api.openai.com/v1/"), "https://api.openai.com/v1");
+    }
+
+    #[test]
+    fn adds_v1_to_a_known_host_without_it() {
+        assert_eq!(normalize_base_url("https://api.openai.com"), "https://api.openai.com/v1");
+        assert_eq!(normalize_base_url(" https://api.openai.com/ "), "https://api.openai.com/v1");
+    }
+
+    #[test]
+    fn leaves_a_custom_host_untouched() {
+        assert_eq!(normalize_base_url("http://localhost:8080"), "http://localhost:8080");
+        assert_eq!(normalize_base_url("https://proxy.example.com/openai"), "https://proxy.example.com/openai");
+    }
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+
+/// The API providers with known quirks; anything else is treated as plain OpenAI.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    #[serde(rename = "openai")]
+    OpenAI,
+    Ollama,
+    Groq,
+    Anthropic,
+    /// Canned local responses for demos and offline testing
+    Mock,
+}
+
+/// How the API key is presented in the auth header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthScheme {
+    /// No auth header is sent
+    None,
+    /// `<header>: Bearer <key>`
+    Bearer,
+    /// `<header>: <key>`
+    Plain,
+}
+
+/// The request and response format a provider speaks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiFormat {
+    /// OpenAI's `/chat/completions`
+    ChatCompletions,
+    /// Anthropic's native `/messages`
+    AnthropicMessages,
+}
+
+impl ApiFormat {
+    /// The endpoint path appended to the base URL.
+    pub fn path(self) -> &'static str {
+        match self {
+            Self::ChatCompletions => "/chat/completions",
+            Self::AnthropicMessages => "/messages",
+        }
+    }
+}
+
+/// Everything that differs between the supported providers.
+#[derive(Debug)]
+pub struct Provider {
+    pub kind: ProviderKind,
+    pub name: &'static str,
+    pub api_format: ApiFormat,
+    pub auth_header: &'static str,
+    pub auth_scheme: AuthScheme,
+    pub default_headers: &'static [(&'static str, &'static str)],
+    pub supports_tools: bool,
+    pub supports_temperature: bool,
+    /// Whether the request may carry an explicit `stream` flag
+    pub supports_stream_flag: bool,
+    /// Whether streamed responses can be asked to report usage via `stream_options`
+    pub supports_stream_usage: bool,
+    pub default_model: &'static str,
+}
+
+const PROVIDERS: &[Provider] = &[
+    Provider {
+        kind: ProviderKind::OpenAI,
+        name: "OpenAI-compatible",
+        api_format: ApiFormat::ChatCompletions,
+        auth_header: "Authorization",
+        auth_scheme: AuthScheme::Bearer,
+        default_headers: &[],
+        supports_tools: true,
+        supports_temperature: true,
+        supports_stream_flag: true,
+        supports_stream_usage: true,
+        default_model: "gpt-4-turbo",
+    },
+    Provider {
+        kind: ProviderKind::Ollama,
+        name: "Ollama",
+        api_format: ApiFormat::ChatCompletions,
+        auth_header: "Authorization",
+        auth_scheme: AuthScheme::None,
+        default_headers: &[],
+        supports_tools: false,
+        supports_temperature: false,
+        supports_stream_flag: false,
+        supports_stream_usage: false,
+        default_model: "llama3.1",
+    },
+    Provider {
+        kind: ProviderKind::Groq,
+        name: "Groq",
+        api_format: ApiFormat::ChatCompletions,
+        auth_header: "Authorization",
+        auth_scheme: AuthScheme::Bearer,
+        default_headers: &[],
+        supports_tools: false,
+        supports_temperature: true,
+        supports_stream_flag: true,
+        supports_stream_usage: false,
+        default_model: "llama-3.1-8b-instant",
+    },
+    Provider {
+        kind: ProviderKind::Anthropic,
+        name: "Anthropic",
+        api_format: ApiFormat::AnthropicMessages,
+        auth_header: "x-api-key",
+        auth_scheme: AuthScheme::Plain,
+        default_headers: &[("anthropic-version", "2023-06-01")],
+        supports_tools: true,
+        supports_temperature: true,
+        supports_stream_flag: true,
+        supports_stream_usage: false,
+        default_model: "claude-3-5-sonnet-latest",
+    },
+    Provider {
+        kind: ProviderKind::Mock,
+        name: "Mock",
+        api_format: ApiFormat::ChatCompletions,
+        auth_header: "Authorization",
+        auth_scheme: AuthScheme::None,
+        default_headers: &[],
+        supports_tools: false,
+        supports_temperature: true,
+        supports_stream_flag: true,
+        supports_stream_usage: false,
+        default_model: "mock",
+    },
+];
+
+impl ProviderKind {
+    /// Guesses the provider from the API base URL.
+    pub fn detect(base_url: &str) -> Self {
+        if base_url.starts_with("mock:") {
+            Self::Mock
+        } else if base_url.contains("ollama") || base_url.contains("localhost") {
+            Self::Ollama
+        } else if base_url.contains("groq") {
+            Self::Groq
+        } else if base_url.contains("anthropic") {
+            Self::Anthropic
+        } else {
+            Self::OpenAI
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "openai" => Some(Self::OpenAI),
+            "ollama" => Some(Self::Ollama),
+            "groq" => Some(Self::Groq),
+            "anthropic" => Some(Self::Anthropic),
+            "mock" => Some(Self::Mock),
+            _ => None,
+        }
+    }
+
+    /// The name used for this provider in settings.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::OpenAI => "openai",
+            Self::Ollama => "ollama",
+            Self::Groq => "groq",
+            Self::Anthropic => "anthropic",
+            Self::Mock => "mock",
+        }
+    }
+
+    pub fn descriptor(self) -> &'static Provider {
+        PROVIDERS
+            .iter()
+            .find(|provider| provider.kind == self)
+            .expect("every provider kind has a descriptor")
+    }
+}
+
+impl Provider {
+    /// The auth header to send with `api_key`, if the provider uses one.
+    pub fn auth_header_value(&self, api_key: &str) -> Option<(&'static str, String)> {
+        match self.auth_scheme {
+            AuthScheme::None => None,
+            AuthScheme::Bearer => Some((self.auth_header, format!("Bearer {}", api_key))),
+            AuthScheme::Plain => Some((self.auth_header, api_key.to_string())),
+        }
+    }
+
+    /// The auth header with the key masked, e.g. `Bearer ***`, for logs.
+    pub fn redacted_auth_header(&self) -> Option<(&'static str, &'static str)> {
+        match self.auth_scheme {
+            AuthScheme::None => None,
+            AuthScheme::Bearer => Some((self.auth_header, "Bearer ***")),
+            AuthScheme::Plain => Some((self.auth_header, "***")),
+        }
+    }
+}
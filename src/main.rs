@@ -2,15 +2,67 @@ mod agent;
 mod cli;
 mod config;
 mod mcp;
+mod util;
 
 use clap::{Parser, Subcommand};
 use tracing_subscriber::{fmt, prelude::*};
 use tracing_subscriber::EnvFilter;
 use anyhow::Result;
+use std::path::PathBuf;
+
+use crate::config::{normalize_base_url, ApiKey, Config};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Model to use, overriding OPENAI_API_MODEL and the config file
+    #[arg(long, global = true)]
+    model: Option<String>,
+
+    /// API base URL, overriding OPENAI_API_BASE_URL and the config file
+    #[arg(long, global = true, value_name = "URL")]
+    base_url: Option<String>,
+
+    /// API key, overriding OPENAI_API_KEY, the config file and the keyring
+    #[arg(long, global = true, value_name = "KEY")]
+    api_key: Option<String>,
+
+    /// Keep conversations in a separate history for this profile (e.g. "coding", "writing")
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Show response timing statistics after each answer
+    #[arg(long, global = true)]
+    timings: bool,
+
+    /// Skip the Context7 MCP server and chat without tools
+    #[arg(long, global = true)]
+    no_mcp: bool,
+
+    /// Print responses as plain text instead of rendering their Markdown
+    #[arg(long, global = true)]
+    no_markdown: bool,
+
+    /// Reasoning effort for o-series models
+    #[arg(long, global = true, value_parser = ["low", "medium", "high"])]
+    reasoning_effort: Option<String>,
+
+    /// Emit logs as JSON lines (also enabled by AI_AGENT_LOG_FORMAT=json)
+    #[arg(long, global = true)]
+    json_logs: bool,
+
+    /// Read the base system prompt from a file
+    #[arg(long, global = true, value_name = "PATH")]
+    system_prompt_file: Option<PathBuf>,
+
+    /// Print each request as it would be sent (API key redacted) instead of calling the API
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Don't ask for confirmation before clearing or replacing the current conversation
+    #[arg(long, short = 'y', global = true)]
+    yes: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -18,28 +70,118 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Start a chat session with the AI
-    Chat,
+    Chat {
+        /// Seed the conversation from a transcript of `user:` / `assistant:` lines
+        #[arg(long, value_name = "PATH")]
+        append_file: Option<PathBuf>,
+    },
+    /// Answer a single prompt and exit
+    Ask {
+        /// The question to ask; piped stdin is appended to it, or used alone without one
+        prompt: Option<String>,
+        /// Continue the most recently active conversation instead of starting a new one
+        #[arg(long)]
+        resume_last: bool,
+        /// Print the answer only once it is complete, even in a terminal
+        #[arg(long)]
+        no_stream: bool,
+    },
+    /// Rewrite all saved conversations in the current format and rebuild the index
+    Compact,
+    /// Store the API key for the configured provider in the OS keyring (read from stdin)
+    SetKey,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing
+    // Parse command line arguments
+    let cli = Cli::parse();
+    
+    // Initialize tracing, as JSON for log aggregators when requested
+    let json_logs = cli.json_logs
+        || std::env::var("AI_AGENT_LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+    let (json_layer, text_layer) = if json_logs {
+        (Some(fmt::layer().json()), None)
+    } else {
+        (None, Some(fmt::layer()))
+    };
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        .with(json_layer)
+        .with(text_layer)
         .with(EnvFilter::from_default_env())
         .init();
     
-    // Parse command line arguments
-    let cli = Cli::parse();
+    // However the program ends from here on, don't leave MCP server processes behind
+    let _mcp_guard = mcp::ShutdownGuard;
+    
+    // Load configuration and apply command line overrides
+    let mut config = Config::load()?;
+    if let Some(url) = &cli.base_url {
+        // A provider default model follows the new provider; a chosen model stays
+        let default_model = config.openai_api_model == config.default_model();
+        config.openai_api_base_url = normalize_base_url(url);
+        if default_model {
+            config.openai_api_model = config.default_model().to_string();
+        }
+    }
+    if let Some(model) = cli.model {
+        config.openai_api_model = model;
+    }
+    if let Some(api_key) = cli.api_key {
+        config.openai_api_key = ApiKey::new(api_key);
+    }
+    if cli.profile.is_some() {
+        config.profile = cli.profile;
+    }
+    config.apply_profile()?;
+    if cli.timings {
+        config.show_timings = true;
+    }
+    if cli.no_mcp {
+        config.mcp_enabled = false;
+    }
+    if cli.no_markdown {
+        config.render_markdown = false;
+    }
+    if cli.reasoning_effort.is_some() {
+        config.reasoning_effort = cli.reasoning_effort;
+    }
+    if cli.dry_run {
+        config.dry_run = true;
+    }
+    if cli.yes {
+        config.confirm_discard = false;
+    }
+    if let Some(path) = &cli.system_prompt_file {
+        let prompt = fs_err::read_to_string(path)?;
+        if prompt.trim().is_empty() {
+            anyhow::bail!("System prompt file {} is empty", path.display());
+        }
+        config.system_prompt = prompt.trim_end().to_string();
+    }
+    
+    // Storing a key is how a missing key gets fixed, so it is the one command that runs without one
+    if !matches!(cli.command, Some(Commands::SetKey)) {
+        config.require_api_key()?;
+    }
     
     // Handle commands
     match cli.command {
-        Some(Commands::Chat) => {
-            cli::start_chat().await?;
+        Some(Commands::Chat { append_file }) => {
+            cli::start_chat(config, append_file.as_deref()).await?;
+        }
+        Some(Commands::Ask { prompt, resume_last, no_stream }) => {
+            cli::ask(config, prompt, resume_last, no_stream).await?;
+        }
+        Some(Commands::Compact) => {
+            cli::compact_history(&config)?;
+        }
+        Some(Commands::SetKey) => {
+            cli::set_key(&config)?;
         }
         None => {
             // Default to chat if no command is provided
-            cli::start_chat().await?;
+            cli::start_chat(config, None).await?;
         }
     }
     
@@ -1,3 +1,5 @@
 pub mod context7;
+pub mod server;
 
-pub use context7::{ensure_mcp_server_running, stop_mcp_server, resolve_library_id, get_library_docs}; 
\ No newline at end of file
+pub use context7::{CONTEXT7, resolve_library_id, get_library_docs};
+pub use server::{McpTool, ShutdownGuard, call_tool, ensure_mcp_servers_running, mcp_tools, retry_failed_mcp_servers, running_mcp_servers, stop_mcp_servers};
@@ -0,0 +1,428 @@
+use anyhow::{Result, anyhow};
+use async_process::{Command, Child, ChildStdin, Stdio};
+use futures::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use futures::StreamExt;
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+use crate::config::{Config, McpConfig};
+
+/// Spawned server processes, keyed by their name in the config.
+static CHILD_PROCESSES: OnceCell<Mutex<HashMap<String, Child>>> = OnceCell::new();
+/// Servers that completed the handshake, keyed like `CHILD_PROCESSES`.
+static CONNECTIONS: OnceCell<Mutex<HashMap<String, Arc<McpConnection>>>> = OnceCell::new();
+/// Servers that failed the handshake this session. They are not started again, so a
+/// server that never answers costs one startup timeout rather than one per request.
+static FAILED: OnceCell<Mutex<HashSet<String>>> = OnceCell::new();
+
+/// How long a server may take to exit after SIGTERM before it is killed.
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// MCP protocol revision sent in the `initialize` handshake.
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+type PendingResponses = Arc<Mutex<HashMap<u64, oneshot::Sender<Value>>>>;
+
+/// A tool offered by one of the MCP servers.
+#[derive(Debug, Clone)]
+pub struct McpTool {
+    pub server: String,
+    pub name: String,
+    pub description: String,
+    /// JSON Schema of the tool's arguments
+    pub input_schema: Value,
+}
+
+/// JSON-RPC over the server's stdin/stdout, one message per line.
+struct McpConnection {
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    /// Callers waiting for the response carrying their request id
+    pending: PendingResponses,
+    next_id: AtomicU64,
+    call_timeout: Duration,
+    /// How long the server may take to answer the `initialize` handshake
+    startup_timeout: Duration,
+    /// Whether request parameters, which may quote the conversation, are logged
+    log_params: bool,
+    /// Tools listed by the server after the handshake
+    tools: Mutex<Vec<McpTool>>,
+}
+
+impl McpConnection {
+    /// Sends a request and waits for the response with the same id.
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        self.request_within(method, params, self.call_timeout).await
+    }
+    
+    /// Like [`Self::request`], with its own limit on how long to wait.
+    async fn request_within(&self, method: &str, params: Value, timeout: Duration) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+        
+        if self.log_params {
+            debug!("MCP request {}: {} {}", id, method, params);
+        } else {
+            debug!("MCP request {}: {}", id, method);
+        }
+        if let Err(e) = self.write(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })).await {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        
+        // A server that never answers must not freeze the chat
+        let response = match time::timeout(timeout, receiver).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => return Err(anyhow!("MCP server closed the connection")),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                error!("MCP call {} timed out after {:?}", method, timeout);
+                return Err(anyhow!("MCP server did not respond within {} seconds", timeout.as_secs()));
+            }
+        };
+        
+        if let Some(error) = response.get("error") {
+            error!("MCP error for {}: {}", method, error);
+            return Err(anyhow!("MCP error: {}", error["message"].as_str().unwrap_or(&error.to_string())));
+        }
+        response.get("result").cloned().ok_or_else(|| anyhow!("Invalid MCP response: {}", response))
+    }
+    
+    async fn notify(&self, method: &str) -> Result<()> {
+        self.write(json!({ "jsonrpc": "2.0", "method": method })).await
+    }
+    
+    async fn write(&self, message: Value) -> Result<()> {
+        let mut line = message.to_string();
+        line.push('\n');
+        let mut stdin = self.stdin.lock().await;
+        stdin.write_all(line.as_bytes()).await?;
+        stdin.flush().await?;
+        Ok(())
+    }
+    
+    /// Performs the `initialize` handshake and fetches the server's tools. The
+    /// server counts as ready only once it has answered the handshake; stdin is
+    /// buffered, so a server that is still starting answers when it gets there.
+    async fn initialize(&self, server: &str) -> Result<()> {
+        let params = json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+        });
+        let result = self.request_within("initialize", params, self.startup_timeout).await?;
+        debug!("MCP server {} info: {}", server, result["serverInfo"]);
+        self.notify("notifications/initialized").await?;
+        
+        if result["capabilities"]["tools"].is_null() {
+            return Ok(());
+        }
+        let mut tools = Vec::new();
+        let mut cursor = Value::Null;
+        loop {
+            let params = if cursor.is_null() { json!({}) } else { json!({ "cursor": cursor }) };
+            let page = self.request("tools/list", params).await?;
+            for tool in page["tools"].as_array().into_iter().flatten() {
+                let Some(name) = tool["name"].as_str() else {
+                    continue;
+                };
+                tools.push(McpTool {
+                    server: server.to_string(),
+                    name: name.to_string(),
+                    description: tool["description"].as_str().unwrap_or_default().to_string(),
+                    input_schema: tool["inputSchema"].clone(),
+                });
+            }
+            cursor = page["nextCursor"].clone();
+            if cursor.is_null() {
+                break;
+            }
+        }
+        *self.tools.lock().unwrap() = tools;
+        Ok(())
+    }
+}
+
+/// Routes each response line from the server to the caller waiting on its id.
+/// When the server exits the pending senders are dropped, failing their calls.
+async fn read_responses(stdout: impl AsyncRead + Unpin, pending: PendingResponses) {
+    let mut lines = BufReader::new(stdout).lines();
+    while let Some(Ok(line)) = lines.next().await {
+        let Ok(message) = serde_json::from_str::<Value>(&line) else {
+            debug!("MCP server: {}", line);
+            continue;
+        };
+        // Notifications and server-initiated requests carry a method
+        if message.get("method").is_some() {
+            debug!("Ignoring MCP message: {}", message["method"]);
+            continue;
+        }
+        let Some(id) = message["id"].as_u64() else {
+            warn!("MCP response without a usable id: {}", message);
+            continue;
+        };
+        match pending.lock().unwrap().remove(&id) {
+            Some(sender) => {
+                let _ = sender.send(message);
+            },
+            None => debug!("Unexpected MCP response id {}", id),
+        }
+    }
+    pending.lock().unwrap().clear();
+}
+
+/// Spawns one server with piped stdio. `Ok(None)` means the command could not
+/// be started, which is logged rather than treated as an error.
+fn spawn_server(name: &str, server: &McpConfig, config: &Config) -> Result<Option<(Child, McpConnection)>> {
+    info!("Starting MCP server {}...", name);
+    let mut child = match Command::new(&server.command)
+        .args(&server.args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to start MCP server {}: {}", name, e);
+                warn!("Continuing without MCP server {} - some functionality may be limited", name);
+                return Ok(None);
+            }
+        };
+    
+    let stdin = child.stdin.take().ok_or_else(|| anyhow!("MCP server stdin was not captured"))?;
+    let stdout = child.stdout.take().ok_or_else(|| anyhow!("MCP server stdout was not captured"))?;
+    if let Some(stderr) = child.stderr.take() {
+        // Server logs would otherwise interleave with the chat
+        let name = name.to_string();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Some(Ok(line)) = lines.next().await {
+                debug!("MCP server {}: {}", name, line);
+            }
+        });
+    }
+    
+    let pending = PendingResponses::default();
+    tokio::spawn(read_responses(stdout, pending.clone()));
+    
+    Ok(Some((child, McpConnection {
+        stdin: tokio::sync::Mutex::new(stdin),
+        pending,
+        next_id: AtomicU64::new(1),
+        call_timeout: Duration::from_secs(config.mcp_timeout_secs),
+        startup_timeout: Duration::from_secs(config.mcp_startup_timeout_secs.max(1)),
+        log_params: config.log_prompts,
+        tools: Mutex::new(Vec::new()),
+    })))
+}
+
+/// Starts every configured server that isn't running yet. Servers whose command
+/// can't be spawned are skipped; a failed handshake is reported as an error
+/// after the remaining servers have been started, and that server is left out
+/// until [`retry_failed_mcp_servers`] is called.
+pub async fn ensure_mcp_servers_running(config: &Config) -> Result<()> {
+    let children = CHILD_PROCESSES.get_or_init(|| Mutex::new(HashMap::new()));
+    let connections = CONNECTIONS.get_or_init(|| Mutex::new(HashMap::new()));
+    let failed = FAILED.get_or_init(|| Mutex::new(HashSet::new()));
+    
+    let mut names: Vec<&String> = config.mcp_servers.servers.keys().collect();
+    names.sort();
+    
+    let mut failures = Vec::new();
+    for name in names {
+        let connection = {
+            let mut guard = children.lock().unwrap();
+            if guard.contains_key(name) || failed.lock().unwrap().contains(name) {
+                continue;
+            }
+            let Some((child, connection)) = spawn_server(name, &config.mcp_servers.servers[name], config)? else {
+                continue;
+            };
+            guard.insert(name.clone(), child);
+            Arc::new(connection)
+        };
+        
+        match connection.initialize(name).await {
+            Ok(()) => {
+                connections.lock().unwrap().insert(name.clone(), connection);
+                info!("MCP server {} started", name);
+            },
+            Err(e) => {
+                stop_server(name).await;
+                failed.lock().unwrap().insert(name.clone());
+                failures.push(format!("{} is not ready: {}", name, e));
+            }
+        }
+    }
+    
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(failures.join("; ")))
+    }
+}
+
+/// Lets servers that failed their handshake be started again by the next
+/// [`ensure_mcp_servers_running`]. Returns how many there were.
+pub fn retry_failed_mcp_servers() -> usize {
+    FAILED.get().map(|failed| failed.lock().unwrap().drain().count()).unwrap_or(0)
+}
+
+/// Names of the servers that have been started and have not exited, sorted.
+pub fn running_mcp_servers() -> Vec<String> {
+    let Some(children) = CHILD_PROCESSES.get() else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = children
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter_map(|(name, child)| matches!(child.try_status(), Ok(None)).then(|| name.clone()))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Tools offered by the servers that completed the handshake.
+pub fn mcp_tools() -> Vec<McpTool> {
+    let Some(connections) = CONNECTIONS.get() else {
+        return Vec::new();
+    };
+    let connections = connections.lock().unwrap();
+    let mut names: Vec<&String> = connections.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .flat_map(|name| connections[name].tools.lock().unwrap().clone())
+        .collect()
+}
+
+pub async fn stop_mcp_servers() -> Result<()> {
+    let names: Vec<String> = CHILD_PROCESSES
+        .get()
+        .map(|children| children.lock().unwrap().keys().cloned().collect())
+        .unwrap_or_default();
+    for name in names {
+        stop_server(&name).await;
+    }
+    Ok(())
+}
+
+async fn stop_server(name: &str) {
+    if let Some(connections) = CONNECTIONS.get() {
+        connections.lock().unwrap().remove(name);
+    }
+    let child = CHILD_PROCESSES.get().and_then(|children| children.lock().unwrap().remove(name));
+
+    if let Some(mut child) = child {
+        info!("Stopping MCP server {}...", name);
+        
+        // Give the server a moment to clean up before it is killed
+        let status = if request_termination(&child) {
+            time::timeout(STOP_GRACE_PERIOD, child.status()).await.ok()
+        } else {
+            None
+        };
+        let status = match status {
+            Some(status) => status,
+            None => {
+                if let Err(e) = child.kill() {
+                    error!("Failed to kill MCP server {} process: {}", name, e);
+                }
+                child.status().await
+            },
+        };
+        
+        match status {
+            Ok(status) => {
+                info!("MCP server {} process exited with status: {}", name, status);
+            },
+            Err(e) => {
+                error!("Failed to get MCP server {} process status: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Stops every MCP server process without needing the async runtime, for exit paths
+/// where [`stop_mcp_servers`] can't run.
+fn stop_mcp_servers_now() {
+    let Some(children) = CHILD_PROCESSES.get() else {
+        return;
+    };
+    // A panic while the lock was held must not keep the processes alive
+    let mut children = children.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    
+    for (name, mut child) in children.drain() {
+        if request_termination(&child) {
+            let deadline = std::time::Instant::now() + STOP_GRACE_PERIOD;
+            while matches!(child.try_status(), Ok(None)) && std::time::Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+        if matches!(child.try_status(), Ok(None)) {
+            if let Err(e) = child.kill() {
+                error!("Failed to kill MCP server {} process: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Asks the process to exit with SIGTERM. Returns false where that isn't possible,
+/// leaving only a kill.
+#[cfg(unix)]
+fn request_termination(child: &Child) -> bool {
+    // SAFETY: kill(2) only sends a signal to the child's pid
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) == 0 }
+}
+
+#[cfg(not(unix))]
+fn request_termination(_child: &Child) -> bool {
+    false
+}
+
+/// Stops the MCP servers when dropped, so they are not left running however the
+/// program ends: an error, a panic or a normal return.
+#[must_use = "the servers are stopped when the guard is dropped"]
+pub struct ShutdownGuard;
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        stop_mcp_servers_now();
+    }
+}
+
+/// Invokes a tool on `server` with `tools/call` and returns the text it produced.
+pub async fn call_tool(server: &str, name: &str, arguments: Value) -> Result<String> {
+    let connection = CONNECTIONS
+        .get()
+        .and_then(|connections| connections.lock().unwrap().get(server).cloned())
+        .ok_or_else(|| anyhow!("MCP server {} is not running", server))?;
+    
+    let result = connection
+        .request("tools/call", json!({ "name": name, "arguments": arguments }))
+        .await?;
+    
+    let text = result["content"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|block| block["text"].as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    
+    if result["isError"].as_bool().unwrap_or(false) {
+        return Err(anyhow!("Tool {} on {} failed: {}", name, server, text));
+    }
+    if text.is_empty() {
+        return Err(anyhow!("Tool {} on {} returned no text: {}", name, server, result));
+    }
+    Ok(text)
+}
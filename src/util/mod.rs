@@ -0,0 +1,186 @@
+/// Rough token count for `text` using the common heuristic of four characters per token.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Returns the longest prefix of `text` containing at most `max_chars` characters,
+/// never splitting a multibyte character.
+pub fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((index, _)) => &text[..index],
+        None => text,
+    }
+}
+
+/// Caps `text` at `max_chars` characters, appending a marker that records how much was cut.
+pub fn truncate_with_marker(text: &str, max_chars: usize) -> String {
+    let total = text.chars().count();
+    if total <= max_chars {
+        return text.to_string();
+    }
+    
+    format!("{}\n[truncated, {} chars omitted]", truncate_chars(text, max_chars), total - max_chars)
+}
+
+/// Shortens `text` to about `max_chars` characters by keeping its beginning and end
+/// around a marker that points to `!last` for the full content.
+pub fn elide_middle(text: &str, max_chars: usize) -> String {
+    let total = text.chars().count();
+    if total <= max_chars {
+        return text.to_string();
+    }
+    
+    let head = truncate_chars(text, max_chars / 2);
+    let tail_len = max_chars - max_chars / 2;
+    let tail_start = text.char_indices().nth(total - tail_len).map(|(index, _)| index).unwrap_or(text.len());
+    
+    format!(
+        "{}\n[… {} chars hidden, use !last to view full]\n{}",
+        head,
+        total - max_chars,
+        &text[tail_start..]
+    )
+}
+
+/// A fenced code block found in Markdown text.
+pub struct CodeBlock {
+    /// The first word of the fence's info string, empty when there is none
+    pub language: String,
+    pub code: String,
+}
+
+/// Collects the fenced (```` ``` ```` or `~~~`) code blocks of a Markdown document, in order.
+/// An unterminated block runs to the end of the text.
+pub fn extract_code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut open: Option<(String, CodeBlock)> = None;
+    
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        match &mut open {
+            None => {
+                let fence_len = trimmed.chars().take_while(|&c| c == '`').count()
+                    .max(trimmed.chars().take_while(|&c| c == '~').count());
+                if fence_len >= 3 {
+                    let fence = trimmed[..fence_len].to_string();
+                    let language = trimmed[fence_len..].split_whitespace().next().unwrap_or_default().to_string();
+                    open = Some((fence, CodeBlock { language, code: String::new() }));
+                }
+            },
+            Some((fence, block)) => {
+                if trimmed.starts_with(fence.as_str()) && trimmed.trim_end().chars().all(|c| fence.starts_with(c)) {
+                    if let Some((_, block)) = open.take() {
+                        blocks.push(block);
+                    }
+                } else {
+                    block.code.push_str(line);
+                    block.code.push('\n');
+                }
+            },
+        }
+    }
+    
+    blocks.extend(open.map(|(_, block)| block));
+    blocks
+}
+
+/// Word-wraps `text` to lines of at most `width` characters, keeping its own line
+/// breaks and splitting words that are longer than a whole line.
+pub fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    
+    for source_line in text.lines() {
+        let mut line = String::new();
+        let mut line_len = 0;
+        
+        for word in source_line.split_whitespace() {
+            let mut word: Vec<char> = word.chars().collect();
+            if line_len > 0 && line_len + 1 + word.len() > width {
+                lines.push(std::mem::take(&mut line));
+                line_len = 0;
+            }
+            while word.len() > width {
+                let rest = word.split_off(width);
+                if line_len > 0 {
+                    lines.push(std::mem::take(&mut line));
+                }
+                lines.push(word.into_iter().collect());
+                line_len = 0;
+                word = rest;
+            }
+            if line_len > 0 {
+                line.push(' ');
+                line_len += 1;
+            }
+            line.extend(word.iter());
+            line_len += word.len();
+        }
+        
+        lines.push(line);
+    }
+    
+    lines
+}
+
+/// Removes ANSI escape sequences and other control characters from untrusted text
+/// before it is printed, keeping newlines and tabs.
+pub fn sanitize_terminal_output(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                // CSI: parameters and intermediates up to a final byte in @..~
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                },
+                // OSC, DCS and similar strings: terminated by BEL or ESC \
+                Some(']') | Some('P') | Some('X') | Some('^') | Some('_') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' {
+                            break;
+                        }
+                        if c == '\x1b' && chars.peek() == Some(&'\\') {
+                            chars.next();
+                            break;
+                        }
+                    }
+                },
+                // Two-character escapes such as ESC c (terminal reset)
+                _ => {}
+            },
+            '\n' | '\t' => output.push(c),
+            c if c.is_control() => {}
+            c => output.push(c),
+        }
+    }
+    
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_csi_sequences() {
+        assert_eq!(sanitize_terminal_output("\x1b[31mred\x1b[0m and \x1b[2J\x1b[1;1Hcleared"), "red and cleared");
+    }
+
+    #[test]
+    fn strips_osc_sequences_with_either_terminator() {
+        assert_eq!(sanitize_terminal_output("a\x1b]0;title\x07b"), "ab");
+        assert_eq!(sanitize_terminal_output("a\x1b]8;;https://evil.example\x1b\\link\x1b]8;;\x1b\\b"), "alinkb");
+    }
+
+    #[test]
+    fn strips_stray_control_bytes_but_keeps_newlines_and_tabs() {
+        assert_eq!(sanitize_terminal_output("one\r\ntwo\tthree\x07\x08\x1bc\x7f!"), "one\ntwo\tthree!");
+    }
+}
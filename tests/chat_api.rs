@@ -0,0 +1,574 @@
+//! End-to-end tests that run the `ask` subcommand against a mock chat completions server.
+
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// A throwaway home directory, so tests never read or write the real config and history.
+struct TestHome(PathBuf);
+
+impl TestHome {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("ai-agent-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn write_config(&self, contents: &str) {
+        let config_dir = self.0.join(".ai-agent");
+        std::fs::create_dir_all(&config_dir).unwrap();
+        std::fs::write(config_dir.join("config.toml"), contents).unwrap();
+    }
+}
+
+impl Drop for TestHome {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn completion(message: Value) -> Value {
+    json!({
+        "id": "chatcmpl-test",
+        "object": "chat.completion",
+        "created": 1700000000,
+        "model": "test-model",
+        "choices": [{ "index": 0, "message": message, "finish_reason": "stop" }],
+        "usage": { "prompt_tokens": 12, "completion_tokens": 3, "total_tokens": 15 }
+    })
+}
+
+async fn ask(home: &Path, base_url: &str, mcp_enabled: bool, prompt: &str) -> Output {
+    run(home, base_url, mcp_enabled, &["ask", prompt], "").await
+}
+
+async fn run(home: &Path, base_url: &str, mcp_enabled: bool, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ai-agent"))
+        .args(args)
+        // Run outside the repository so a developer's .env can't leak into the test
+        .current_dir(home)
+        .env("HOME", home)
+        .env("OPENAI_API_KEY", "test-key")
+        .env("OPENAI_API_BASE_URL", base_url)
+        .env("OPENAI_API_MODEL", "test-model")
+        .env("MCP_ENABLED", mcp_enabled.to_string())
+        .env("SINGLE_INSTANCE", "false")
+        .env("USE_KEYRING", "false")
+        // Keep retried failures fast
+        .env("RETRY_BACKOFF_MS", "1")
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run ai-agent");
+    let mut pipe = child.stdin.take().unwrap();
+    pipe.write_all(stdin.as_bytes()).await.unwrap();
+    drop(pipe);
+    child.wait_with_output().await.expect("failed to run ai-agent")
+}
+
+async fn received_request(server: &MockServer) -> Value {
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1, "expected exactly one request");
+    serde_json::from_slice(&requests[0].body).unwrap()
+}
+
+#[tokio::test]
+async fn sends_model_messages_and_auth() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(completion(json!({
+            "role": "assistant",
+            "content": "Hello back"
+        }))))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hello").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "Hello back");
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests[0].headers.get("authorization").unwrap(), "Bearer test-key");
+
+    let body = received_request(&server).await;
+    assert_eq!(body["model"], "test-model");
+    assert_eq!(body["stream"], false);
+    assert!(body.get("tools").is_none(), "tools must not be sent with MCP disabled");
+    assert!(body.get("max_tokens").is_none() && body.get("top_p").is_none(), "unset parameters must be omitted");
+
+    let messages = body["messages"].as_array().unwrap();
+    assert_eq!(messages.first().unwrap()["role"], "system");
+    assert_eq!(messages.last().unwrap()["role"], "user");
+    assert_eq!(messages.last().unwrap()["content"], "Hello");
+}
+
+#[tokio::test]
+async fn command_line_flags_override_the_environment() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(completion(json!({
+            "role": "assistant",
+            "content": "Overridden"
+        }))))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+    let base_url = format!("{}/v1", server.uri());
+
+    let output = run(
+        &home.0,
+        "http://127.0.0.1:9/v1",
+        false,
+        &["--model", "flag-model", "--base-url", &base_url, "--api-key", "flag-key", "ask", "Hi"],
+        "",
+    )
+    .await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests[0].headers.get("authorization").unwrap(), "Bearer flag-key");
+    assert_eq!(received_request(&server).await["model"], "flag-model");
+}
+
+#[tokio::test]
+async fn dry_run_prints_the_request_without_sending_it() {
+    let server = MockServer::start().await;
+    let home = TestHome::new();
+
+    let output = run(&home.0, &format!("{}/v1", server.uri()), false, &["--dry-run", "ask", "Hello"], "").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("/v1/chat/completions"), "stdout: {}", stdout);
+    assert!(stdout.contains("Bearer ***") && !stdout.contains("test-key"), "stdout: {}", stdout);
+    assert!(stdout.contains("\"content\": \"Hello\""), "stdout: {}", stdout);
+    assert!(stdout.trim_end().ends_with("[dry-run: no request sent]"), "stdout: {}", stdout);
+    assert!(server.received_requests().await.unwrap().is_empty());
+    // Nothing reaches the history
+    let history = home.0.join(".ai-agent/history");
+    assert!(!history.join("conversations.json").exists());
+    assert!(!history.join("last_conversation").exists());
+}
+
+#[tokio::test]
+async fn appends_piped_stdin_to_the_prompt() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(completion(json!({
+            "role": "assistant",
+            "content": "A summary"
+        }))))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = run(
+        &home.0,
+        &format!("{}/v1", server.uri()),
+        false,
+        &["ask", "summarize this"],
+        "line one\nline two\n",
+    )
+    .await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let body = received_request(&server).await;
+    let messages = body["messages"].as_array().unwrap();
+    assert_eq!(messages.last().unwrap()["content"], "summarize this\n\nline one\nline two");
+}
+
+#[tokio::test]
+async fn sends_configured_generation_parameters() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(completion(json!({
+            "role": "assistant",
+            "content": "Short"
+        }))))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+    home.write_config("temperature = 0.25\ntop_p = 0.5\nmax_tokens = 256\n");
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hi").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let body = received_request(&server).await;
+    assert_eq!(body["temperature"], 0.25);
+    assert_eq!(body["top_p"], 0.5);
+    assert_eq!(body["max_tokens"], 256);
+}
+
+#[tokio::test]
+async fn project_config_overrides_the_home_config() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(completion(json!({
+            "role": "assistant",
+            "content": "Short"
+        }))))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+    home.write_config("temperature = 0.25\ntop_p = 0.5\n");
+    // `ask` runs in the test home, so this is the current directory's project file
+    std::fs::write(home.0.join("ai-agent.toml"), "temperature = 0.75\n").unwrap();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hi").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let body = received_request(&server).await;
+    assert_eq!(body["temperature"], 0.75);
+    assert_eq!(body["top_p"], 0.5);
+}
+
+#[tokio::test]
+async fn sends_tool_definitions_when_mcp_is_enabled() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(completion(json!({
+            "role": "assistant",
+            "content": "No tools needed"
+        }))))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+    // The agent carries on with tools enabled even when the server can't be spawned
+    home.write_config("[mcp_servers.context7]\ncommand = \"ai-agent-test-missing-mcp-server\"\nargs = []\n");
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), true, "Which tools?").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let body = received_request(&server).await;
+    let names: Vec<&str> = body["tools"]
+        .as_array()
+        .expect("tools should be sent")
+        .iter()
+        .map(|tool| tool["function"]["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["mcp_context7_resolve_library_id", "mcp_context7_get_library_docs"]);
+    assert!(body["tools"].as_array().unwrap().iter().all(|tool| tool["type"] == "function"));
+}
+
+fn tool_call_completion(name: &str) -> Value {
+    completion(json!({
+        "role": "assistant",
+        "content": null,
+        "tool_calls": [{
+            "id": "call_1",
+            "type": "function",
+            "function": { "name": name, "arguments": "{}" }
+        }]
+    }))
+}
+
+#[tokio::test]
+async fn sends_tool_results_back_to_the_model() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(tool_call_completion("launch_rockets")))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(completion(json!({
+            "role": "assistant",
+            "content": "I can't launch rockets."
+        }))))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Do something").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "I can't launch rockets.");
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    let follow_up: Value = serde_json::from_slice(&requests[1].body).unwrap();
+    let messages = follow_up["messages"].as_array().unwrap();
+    let call = &messages[messages.len() - 2];
+    assert_eq!(call["role"], "assistant");
+    assert_eq!(call["tool_calls"][0]["function"]["name"], "launch_rockets");
+    let result = messages.last().unwrap();
+    assert_eq!(result["role"], "tool");
+    assert_eq!(result["tool_call_id"], "call_1");
+    assert_eq!(result["name"], "launch_rockets");
+    assert_eq!(result["content"], "Unsupported tool call: launch_rockets");
+}
+
+#[tokio::test]
+async fn resumed_conversations_keep_their_tool_turns() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(tool_call_completion("launch_rockets")))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(completion(json!({
+            "role": "assistant",
+            "content": "I can't launch rockets."
+        }))))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+    let base_url = format!("{}/v1", server.uri());
+
+    let first = ask(&home.0, &base_url, false, "Do something").await;
+    assert!(first.status.success(), "stderr: {}", String::from_utf8_lossy(&first.stderr));
+    let second = run(&home.0, &base_url, false, &["ask", "--resume-last", "Why not?"], "").await;
+    assert!(second.status.success(), "stderr: {}", String::from_utf8_lossy(&second.stderr));
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 3);
+    let resumed: Value = serde_json::from_slice(&requests[2].body).unwrap();
+    let roles: Vec<&str> = resumed["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|m| m["role"] != "system")
+        .map(|m| m["role"].as_str().unwrap())
+        .collect();
+    assert_eq!(roles, ["user", "assistant", "tool", "assistant", "user"]);
+    let call = resumed["messages"].as_array().unwrap().iter().find(|m| m["tool_calls"].is_array()).unwrap();
+    assert_eq!(call["tool_calls"][0]["id"], "call_1");
+}
+
+#[tokio::test]
+async fn stops_after_max_tool_iterations() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(tool_call_completion("launch_rockets")))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+    home.write_config("max_tool_iterations = 2\n");
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Do something").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Unsupported tool call: launch_rockets"), "stdout: {}", stdout);
+    assert!(stdout.contains("reached max tool iterations"), "stdout: {}", stdout);
+}
+
+#[tokio::test]
+async fn accepts_responses_without_usage() {
+    let server = MockServer::start().await;
+    let mut response = completion(json!({ "role": "assistant", "content": "No usage here" }));
+    response.as_object_mut().unwrap().remove("usage");
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(response))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hi").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "No usage here");
+}
+
+#[tokio::test]
+async fn surfaces_error_statuses() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(500).set_body_string("upstream exploded"))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hi").await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("500"), "stderr: {}", stderr);
+    assert!(stderr.contains("upstream exploded"), "stderr: {}", stderr);
+}
+
+#[tokio::test]
+async fn retries_rate_limits_and_server_errors() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .with_priority(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(completion(json!({
+            "role": "assistant",
+            "content": "Third time lucky"
+        }))))
+        .with_priority(3)
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hi").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Third time lucky"));
+    assert_eq!(server.received_requests().await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn does_not_retry_client_errors() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(400).set_body_string("bad request"))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hi").await;
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("bad request"));
+    assert_eq!(server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn explains_auth_failures_from_the_error_envelope() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "error": { "message": "Incorrect API key provided", "type": "invalid_request_error", "code": "invalid_api_key" }
+        })))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hi").await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("authentication failed (401): Incorrect API key provided"), "stderr: {}", stderr);
+    assert!(stderr.contains("check your API key"), "stderr: {}", stderr);
+    assert!(!stderr.contains("invalid_request_error"), "raw JSON leaked: {}", stderr);
+}
+
+#[tokio::test]
+async fn reports_exhausted_rate_limits() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(429).set_body_json(json!({
+            "error": { "message": "Rate limit reached for requests", "type": "requests", "code": "rate_limit_exceeded" }
+        })))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hi").await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("rate limit exceeded (429): Rate limit reached for requests"), "stderr: {}", stderr);
+}
+
+#[tokio::test]
+async fn hints_at_a_missing_v1_suffix_on_404() {
+    let server = MockServer::start().await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &server.uri(), false, "Hi").await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no /v1 suffix"), "stderr: {}", stderr);
+}
+
+#[tokio::test]
+async fn explains_non_json_responses() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw("<html>Just a moment...</html>", "text/html"))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hi").await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("not valid JSON"), "stderr: {}", stderr);
+    assert!(stderr.contains("text/html"), "stderr: {}", stderr);
+    assert!(stderr.contains("Just a moment"), "stderr: {}", stderr);
+}
+
+#[tokio::test]
+async fn speaks_the_anthropic_messages_api() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/messages"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "id": "msg_test",
+            "type": "message",
+            "role": "assistant",
+            "model": "test-model",
+            "content": [{ "type": "text", "text": "Hello from Claude" }],
+            "stop_reason": "end_turn",
+            "usage": { "input_tokens": 12, "output_tokens": 4 }
+        })))
+        .mount(&server)
+        .await;
+    let home = TestHome::new();
+    home.write_config("provider = \"anthropic\"\n");
+
+    let output = ask(&home.0, &format!("{}/v1", server.uri()), false, "Hello").await;
+
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "Hello from Claude");
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests[0].headers.get("x-api-key").unwrap(), "test-key");
+    assert!(requests[0].headers.get("anthropic-version").is_some());
+    assert!(requests[0].headers.get("authorization").is_none());
+
+    let body = received_request(&server).await;
+    assert!(body["system"].as_str().is_some_and(|system| !system.is_empty()));
+    assert!(body["max_tokens"].as_u64().is_some());
+    let messages = body["messages"].as_array().unwrap();
+    assert!(messages.iter().all(|message| message["role"] != "system"));
+    assert_eq!(messages.last().unwrap()["content"], "Hello");
+}